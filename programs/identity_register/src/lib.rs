@@ -1,13 +1,19 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hash;
 use anchor_spl::{
     associated_token::AssociatedToken,
     metadata::{
-        create_master_edition_v3, create_metadata_accounts_v3, CreateMasterEditionV3,
-        CreateMetadataAccountsV3, Metadata,
+        burn_nft, create_master_edition_v3, create_metadata_accounts_v3, freeze_delegated_account,
+        thaw_delegated_account, update_metadata_accounts_v2, verify_sized_collection_item, BurnNft,
+        CreateMasterEditionV3, CreateMetadataAccountsV3, FreezeDelegatedAccount, Metadata,
+        MetadataAccount, ThawDelegatedAccount, UpdateMetadataAccountsV2, VerifySizedCollectionItem,
+    },
+    token::{
+        approve, close_account, mint_to, set_authority, spl_token::instruction::AuthorityType,
+        transfer, Approve, CloseAccount, Mint, MintTo, SetAuthority, Token, TokenAccount, Transfer,
     },
-    token::{mint_to, transfer, Mint, MintTo, Token, TokenAccount, Transfer},
 };
-use mpl_token_metadata::types::{Creator, DataV2};
+use mpl_token_metadata::types::{Collection, CollectionDetails, Creator, DataV2};
 
 // This is your program's unique ID. Get it after you build/deploy.
 declare_id!("6a4hgLX7rnVaz3U8EDrMkCuqwXkZreRB8u17KBAeoJCn");
@@ -16,339 +22,3893 @@ declare_id!("6a4hgLX7rnVaz3U8EDrMkCuqwXkZreRB8u17KBAeoJCn");
 pub mod identity_register {
     use super::*;
 
-    // This is the main instruction. It creates the identity account.
-    pub fn register_identity(ctx: Context<RegisterIdentity>, username: String, symbol: String, uri: String) -> Result<()> {
-        let identity = &mut ctx.accounts.identity_account;
-        // Enforce a maximum username length to avoid allocating less space than needed
-        // and to provide a clear, deterministic program error when clients pass too-long usernames.
-        if username.len() > 50 {
-            return err!(ErrorCode::UsernameTooLong);
-        }
+    // One-time setup by the program's upgrade authority. Creates the registry
+    // config and the collection NFT that every identity NFT is verified into.
+    pub fn initialize_collection(ctx: Context<InitializeCollection>, name: String, symbol: String, uri: String) -> Result<()> {
+        let name = validate_display_name(&name)?;
+        validate_symbol(&symbol)?;
+        validate_uri(&uri)?;
 
-        if uri.len() > 200 {
-            return err!(ErrorCode::UriTooLong);
-        }
-        
-        identity.authority = ctx.accounts.authority.key();
-        identity.username = username.clone();
-        identity.uri = uri.clone(); // This URI will point to the NFT's off-chain JSON metadata
-        identity.bump = ctx.bumps.identity_account;
-        
-        msg!("Identity account created for {} with username: {}", identity.authority, identity.username);
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.admin.key();
+        config.collection_mint = ctx.accounts.collection_mint.key();
+        config.authority_change_delay = DEFAULT_AUTHORITY_CHANGE_DELAY;
+        config.bump = ctx.bumps.config;
 
-        msg!("Minting Identity NFT...");
+        let registry_authority_seeds: &[&[&[u8]]] = &[&[b"registry_authority", &[ctx.bumps.registry_authority]]];
 
-        // CPI 1: Mint 1 token to the user's token account
+        // CPI 1: Mint the collection NFT to the registry PDA's token account
         mint_to(
-            CpiContext::new(
+            CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
                 MintTo {
-                    mint: ctx.accounts.mint.to_account_info(),
-                    to: ctx.accounts.token_account.to_account_info(),
-                    authority: ctx.accounts.authority.to_account_info(),
+                    mint: ctx.accounts.collection_mint.to_account_info(),
+                    to: ctx.accounts.collection_token_account.to_account_info(),
+                    authority: ctx.accounts.registry_authority.to_account_info(),
                 },
+                registry_authority_seeds,
             ),
-            1, // Mint 1 token
+            1,
         )?;
 
-        msg!("Token minted");
-
-        // CPI 2: Create the Metaplex Metadata Account
+        // CPI 2: Create the collection metadata. `collection_details` marks it as a
+        // sized collection, so Metaplex tracks how many identities are verified into it.
         let creators = vec![
             Creator {
-                address: ctx.accounts.authority.key(),
-                verified: true, // The signer is verified as a creator
+                address: ctx.accounts.registry_authority.key(),
+                verified: true,
                 share: 100,
             }
         ];
 
         create_metadata_accounts_v3(
-            CpiContext::new(
+            CpiContext::new_with_signer(
                 ctx.accounts.token_metadata_program.to_account_info(),
                 CreateMetadataAccountsV3 {
-                    metadata: ctx.accounts.metadata_account.to_account_info(),
-                    mint: ctx.accounts.mint.to_account_info(),
-                    mint_authority: ctx.accounts.authority.to_account_info(),
-                    payer: ctx.accounts.authority.to_account_info(),
-                    update_authority: ctx.accounts.authority.to_account_info(),
+                    metadata: ctx.accounts.collection_metadata.to_account_info(),
+                    mint: ctx.accounts.collection_mint.to_account_info(),
+                    mint_authority: ctx.accounts.registry_authority.to_account_info(),
+                    payer: ctx.accounts.admin.to_account_info(),
+                    update_authority: ctx.accounts.registry_authority.to_account_info(),
                     system_program: ctx.accounts.system_program.to_account_info(),
                     rent: ctx.accounts.rent.to_account_info(),
                 },
+                registry_authority_seeds,
             ),
             DataV2 {
-                name: username, // Use the username from the instruction
-                symbol: symbol, // Use the symbol from the instruction
-                uri: uri,       // Use the URI from the instruction
+                name,
+                symbol,
+                uri,
                 seller_fee_basis_points: 0,
                 creators: Some(creators),
                 collection: None,
                 uses: None,
             },
-            false, // is_mutable: We make the NFT immutable
-            true,  // update_authority_is_signer
-            None,  // collection_details
+            true, // is_mutable
+            true, // update_authority_is_signer
+            Some(CollectionDetails::V1 { size: 0 }),
         )?;
 
-        msg!("Metadata account created");
-
-        // CPI 3: Create the Metaplex Master Edition Account (locks supply to 1)
+        // CPI 3: Create the collection master edition
         create_master_edition_v3(
-            CpiContext::new(
+            CpiContext::new_with_signer(
                 ctx.accounts.token_metadata_program.to_account_info(),
                 CreateMasterEditionV3 {
-                    edition: ctx.accounts.master_edition_account.to_account_info(),
-                    mint: ctx.accounts.mint.to_account_info(),
-                    update_authority: ctx.accounts.authority.to_account_info(),
-                    mint_authority: ctx.accounts.authority.to_account_info(),
-                    payer: ctx.accounts.authority.to_account_info(),
-                    metadata: ctx.accounts.metadata_account.to_account_info(),
+                    edition: ctx.accounts.collection_master_edition.to_account_info(),
+                    mint: ctx.accounts.collection_mint.to_account_info(),
+                    update_authority: ctx.accounts.registry_authority.to_account_info(),
+                    mint_authority: ctx.accounts.registry_authority.to_account_info(),
+                    payer: ctx.accounts.admin.to_account_info(),
+                    metadata: ctx.accounts.collection_metadata.to_account_info(),
                     token_program: ctx.accounts.token_program.to_account_info(),
                     system_program: ctx.accounts.system_program.to_account_info(),
                     rent: ctx.accounts.rent.to_account_info(),
                 },
+                registry_authority_seeds,
             ),
-            Some(0), // Max supply 0 = locked. This is what makes it a 1-of-1 NFT.
+            Some(0),
         )?;
 
-        msg!("Master Edition created. Identity NFT mint complete!");
+        msg!("Identity collection created: {}", config.collection_mint);
+        Ok(())
+    }
+
+    // This is the main instruction. It creates the identity account.
+    // `operator` is an optional first operator key. AI agents must declare one.
+    // The NFT symbol is set from `kind`, see `IdentityKind::symbol`.
+    pub fn register_identity(
+        ctx: Context<RegisterIdentity>,
+        username: String,
+        uri: String,
+        metadata_hash: [u8; 32],
+        kind: IdentityKind,
+        operator: Option<Operator>,
+    ) -> Result<()> {
+        ctx.accounts.register(&ctx.bumps, username, uri, metadata_hash, kind, operator)
+    }
+
+    // Registers an identity vouched for by an existing (parent) identity. The
+    // parent's owner, or its operator with `OPERATOR_MANAGE_SUB_IDENTITIES`,
+    // signs, and the link is stored on the child so clients can walk up to the root.
+    pub fn register_sub_identity(
+        ctx: Context<RegisterSubIdentity>,
+        username: String,
+        uri: String,
+        metadata_hash: [u8; 32],
+        kind: IdentityKind,
+        operator: Option<Operator>,
+    ) -> Result<()> {
+        let parent = &ctx.accounts.parent_identity;
+        parent.authorize(&ctx.accounts.parent_signer.key(), OPERATOR_MANAGE_SUB_IDENTITIES)?;
+        if parent.suspended {
+            return err!(ErrorCode::IdentitySuspended);
+        }
+
+        let depth = parent.depth.checked_add(1).ok_or(ErrorCode::Overflow)?;
+        if depth > MAX_SUB_IDENTITY_DEPTH {
+            return err!(ErrorCode::SubIdentityTooDeep);
+        }
+
+        let parent_key = parent.key();
+        ctx.accounts.register.register(&ctx.bumps.register, username, uri, metadata_hash, kind, operator)?;
 
+        let parent = &mut ctx.accounts.parent_identity;
+        parent.sub_identity_count = parent.sub_identity_count.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        let child = &mut ctx.accounts.register.identity_account;
+        child.parent = parent_key;
+        child.depth = depth;
+
+        msg!("Sub-identity {} registered under {} at depth {}", child.key(), parent_key, depth);
         Ok(())
     }
 
-    // Initialize a reputation account for an existing identity
-    pub fn initialize_reputation(ctx: Context<InitializeReputation>) -> Result<()> {
-        let reputation_account = &mut ctx.accounts.reputation_account;
-        
-        reputation_account.authority = ctx.accounts.authority.key();
-        reputation_account.total_transactions = 0;
-        reputation_account.total_volume = 0;
-        reputation_account.total_reviews = 0;
-        reputation_account.total_rating_score = 0;
-        reputation_account.bump = ctx.bumps.reputation_account;
+    // Parent only. Revokes a sub-identity, which then can't take payments or
+    // register sub-identities of its own. Clients should treat a chain with a
+    // revoked link as untrusted from that link down. A revoked child no longer
+    // counts towards the parent's `sub_identity_count`.
+    pub fn revoke_sub_identity(ctx: Context<RevokeSubIdentity>) -> Result<()> {
+        ctx.accounts.parent_identity.authorize(&ctx.accounts.parent_signer.key(), OPERATOR_MANAGE_SUB_IDENTITIES)?;
+        ctx.accounts.child_identity.revoked = true;
 
-        msg!("Reputation account created for authority {}", reputation_account.authority);
+        let parent = &mut ctx.accounts.parent_identity;
+        parent.sub_identity_count = parent.sub_identity_count.checked_sub(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!(
+            "Sub-identity {} revoked by {}",
+            ctx.accounts.child_identity.key(),
+            ctx.accounts.parent_identity.key()
+        );
         Ok(())
     }
 
-    pub fn log_service_transaction(ctx: Context<LogServiceTransaction>, amount: u64) -> Result<()> {
-        // 1. CPI to transfer tokens from payer to agent
-        msg!("Transferring {} tokens from payer to agent", amount);
-        
-        // We use the `transfer` function from the `anchor_spl::token` crate
-        transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.payer_token_account.to_account_info(),
-                    to: ctx.accounts.agent_token_account.to_account_info(),
-                    authority: ctx.accounts.payer.to_account_info(),
+    // Update the identity's metadata URI (and optionally its display name),
+    // keeping the identity account and the NFT metadata in sync. The replaced
+    // URI and hash are archived in a `MetadataVersion` PDA first.
+    pub fn update_identity(ctx: Context<UpdateIdentity>, uri: String, metadata_hash: [u8; 32], display_name: Option<String>) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_UPDATE_METADATA)?;
+        validate_uri(&uri)?;
+        let display_name = match display_name {
+            Some(display_name) => {
+                let display_name = validate_display_name(&display_name)?;
+                // Lowercasing can make the key longer than the name (e.g. 'İ'),
+                // and it has to fit in the claim
+                let key = display_name_key(&display_name);
+                if key.len() > MAX_USERNAME_LENGTH {
+                    return err!(ErrorCode::DisplayNameTooLong);
+                }
+                check_reserved_words(&key)?;
+                display_name
+            }
+            None => ctx.accounts.identity_account.display_name.clone(),
+        };
+
+        // Claim the display name in the username namespace. A restyling of the
+        // identity's own username resolves to its username claim.
+        let identity_key = ctx.accounts.identity_account.key();
+        let claim = &mut ctx.accounts.display_name_claim;
+        if claim.identity == Pubkey::default() {
+            claim.authority = ctx.accounts.identity_account.authority;
+            claim.identity = identity_key;
+            claim.username = display_name_key(&display_name);
+            claim.bump = ctx.bumps.display_name_claim;
+        } else if claim.identity != identity_key {
+            return err!(ErrorCode::DisplayNameTaken);
+        }
+
+        // Release the claim on the display name being replaced, unless it is
+        // the username claim or the same claim as the new one
+        let previous_key = display_name_key(&ctx.accounts.identity_account.display_name);
+        let releases_previous = previous_key != ctx.accounts.identity_account.username
+            && previous_key != display_name_key(&display_name);
+        match &ctx.accounts.previous_display_name_claim {
+            Some(previous) if releases_previous => previous.close(ctx.accounts.authority.to_account_info())?,
+            None if !releases_previous => {}
+            _ => return err!(ErrorCode::DisplayNameClaimMismatch),
+        }
+
+        let identity = &mut ctx.accounts.identity_account;
+        let current_slot = Clock::get()?.slot;
+
+        // Archive the version being replaced. Archives are never closed.
+        let archive = &mut ctx.accounts.metadata_version;
+        archive.identity = identity.key();
+        archive.version = identity.metadata_version;
+        archive.uri = identity.uri.clone();
+        archive.metadata_hash = identity.metadata_hash;
+        archive.slot = identity.metadata_slot;
+        archive.replaced_slot = current_slot;
+        archive.bump = ctx.bumps.metadata_version;
+
+        // The hash always changes together with the URI it commits to
+        identity.uri = uri.clone();
+        identity.metadata_hash = metadata_hash;
+        identity.metadata_version = identity.metadata_version.checked_add(1).ok_or(ErrorCode::Overflow)?;
+        identity.metadata_slot = current_slot;
+        identity.display_name = display_name.clone();
+
+        // Keep everything else in the metadata as it was at registration
+        let metadata = &ctx.accounts.metadata_account;
+        let data = DataV2 {
+            name: display_name,
+            symbol: identity.kind.symbol().to_string(),
+            uri,
+            seller_fee_basis_points: metadata.seller_fee_basis_points,
+            creators: metadata.creators.clone(),
+            collection: metadata.collection.clone(),
+            uses: metadata.uses.clone(),
+        };
+
+        update_metadata_accounts_v2(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                UpdateMetadataAccountsV2 {
+                    metadata: ctx.accounts.metadata_account.to_account_info(),
+                    update_authority: ctx.accounts.registry_authority.to_account_info(),
                 },
+                &[&[b"registry_authority", &[ctx.bumps.registry_authority]]],
             ),
-            amount,
+            None,       // new_update_authority: stays with the registry PDA
+            Some(data),
+            None,       // primary_sale_happened
+            None,       // is_mutable
         )?;
-        msg!("Transfer complete");
 
-        // 2. Update the agent's reputation account
-        let reputation_account = &mut ctx.accounts.reputation_account;
-        
-        // Use checked_add to prevent overflow
-        reputation_account.total_transactions = reputation_account.total_transactions.checked_add(1)
-            .ok_or(ErrorCode::Overflow)?;
-        
-        reputation_account.total_volume = reputation_account.total_volume.checked_add(amount)
-            .ok_or(ErrorCode::Overflow)?;
-        
-        msg!("Reputation updated for {}", reputation_account.authority);
-        msg!("  Total Transactions: {}", reputation_account.total_transactions);
-        msg!("  Total Volume: {}", reputation_account.total_volume);
+        msg!("Identity updated for {}", identity.authority);
+        msg!("  Display Name: {}", identity.display_name);
+        msg!("  URI: {}", identity.uri);
+        msg!("  Metadata hash: {:?}", identity.metadata_hash);
+        msg!("  Metadata version: {}", identity.metadata_version);
 
         Ok(())
     }
-}
 
-// Custom program errors
-#[error_code]
-pub enum ErrorCode {
-    #[msg("Username is too long (max 50 characters)")]
-    UsernameTooLong,
+    // Deregister an identity. Burns the identity NFT, closes the identity PDA,
+    // its service endpoints (and optionally the reputation PDA) and returns the
+    // rent to the authority.
+    // Tombstones are left behind so neither the username nor the wallet can
+    // register again, since a fresh identity would start from a clean
+    // reputation. The wallet's other identities are not affected.
+    // Vouched sub-identities must be revoked or closed first. A vouched child
+    // that closes is taken out of its parent's count.
+    pub fn close_identity(ctx: Context<CloseIdentity>) -> Result<()> {
+        // A display name claim of its own must be released with the identity
+        let identity = &ctx.accounts.identity_account;
+        if ctx.accounts.display_name_claim.is_some() != (display_name_key(&identity.display_name) != identity.username) {
+            return err!(ErrorCode::DisplayNameClaimMismatch);
+        }
 
-    #[msg("URI is too long (max 200 characters)")]
-    UriTooLong,
+        // A child still vouched for is counted on its parent
+        let is_vouched = identity.parent != Pubkey::default() && !identity.revoked;
+        match ctx.accounts.parent_identity.as_mut() {
+            Some(parent) if is_vouched => {
+                parent.sub_identity_count = parent.sub_identity_count.checked_sub(1)
+                    .ok_or(ErrorCode::Overflow)?;
+            }
+            None if !is_vouched => {}
+            _ => return err!(ErrorCode::NotParentIdentity),
+        }
 
-    #[msg("Arithmetic overflow")]
-    Overflow,
-}
+        // CPI 1: Thaw the soulbound NFT so it can be burned
+        thaw_delegated_account(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                ThawDelegatedAccount {
+                    metadata: ctx.accounts.metadata_account.to_account_info(),
+                    delegate: ctx.accounts.registry_authority.to_account_info(),
+                    token_account: ctx.accounts.token_account.to_account_info(),
+                    edition: ctx.accounts.master_edition_account.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                },
+                &[&[b"registry_authority", &[ctx.bumps.registry_authority]]],
+            ),
+        )?;
 
-// This struct defines all the accounts required by our `register_identity` instruction
-#[derive(Accounts)]
-#[instruction(username: String, symbol: String, uri: String)] // Make instruction args available
-pub struct RegisterIdentity<'info> {
-    
-    // This creates the new PDA account
-    #[account(
-        init,
-        payer = authority,
-        // Space = 8 (discriminator) + 32 (authority) + (4 + 50) (username) + (4 + 200) (uri) + 1 (bump)
-        space = 8 + 32 + 4 + 50 + 4 + 200 + 1,
-        // Seeds make the PDA unique to the user
-        seeds = [b"identity", authority.key().as_ref()],
-        bump
-    )]
-    pub identity_account: Account<'info, IdentityAccount>,
+        // Hand the close authority back to the owner, so Metaplex can close the
+        // token account when it burns the NFT
+        set_authority(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                SetAuthority {
+                    current_authority: ctx.accounts.registry_authority.to_account_info(),
+                    account_or_mint: ctx.accounts.token_account.to_account_info(),
+                },
+                &[&[b"registry_authority", &[ctx.bumps.registry_authority]]],
+            ),
+            AuthorityType::CloseAccount,
+            None,
+        )?;
 
-    // The user who is creating the identity (and paying for it)
-    #[account(mut)]
-    pub authority: Signer<'info>,
-    
-    // The Solana System Program, required to create new accounts
-    pub system_program: Program<'info, System>,
+        // CPI 2: Burn the NFT. Metaplex also closes the token, metadata and
+        // master edition accounts and refunds their rent to the owner.
+        // The collection metadata is passed so Metaplex can decrement the collection size.
+        burn_nft(
+            CpiContext::new(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                BurnNft {
+                    metadata: ctx.accounts.metadata_account.to_account_info(),
+                    owner: ctx.accounts.authority.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    token: ctx.accounts.token_account.to_account_info(),
+                    edition: ctx.accounts.master_edition_account.to_account_info(),
+                    spl_token: ctx.accounts.token_program.to_account_info(),
+                },
+            )
+            .with_remaining_accounts(vec![ctx.accounts.collection_metadata.to_account_info()]),
+            Some(ctx.accounts.collection_metadata.key()),
+        )?;
 
-    #[account(
-        init, // We are initializing this mint account
-        payer = authority,
-        mint::decimals = 0, // NFTs must have 0 decimals
-        mint::authority = authority, // The user is the mint authority
-        mint::freeze_authority = authority, // The user is the freeze authority
-    )]
-    pub mint: Account<'info, Mint>,
+        msg!("Identity NFT burned");
 
-    #[account(
-        init, // Create the user's token account
-        payer = authority,
-        associated_token::mint = mint,
-        associated_token::authority = authority,
-    )]
-    pub token_account: Account<'info, TokenAccount>, // The user's ATA
+        // The endpoints address is fixed, so the caller can't skip it to leave
+        // the closed identity's URLs and their rent behind
+        let endpoints_info = ctx.accounts.endpoints.to_account_info();
+        if !endpoints_info.data_is_empty() && endpoints_info.owner == &crate::ID {
+            let authority_info = ctx.accounts.authority.to_account_info();
+            let refund = authority_info.lamports().checked_add(endpoints_info.lamports())
+                .ok_or(ErrorCode::Overflow)?;
+            **authority_info.try_borrow_mut_lamports()? = refund;
+            **endpoints_info.try_borrow_mut_lamports()? = 0;
+            endpoints_info.assign(&System::id());
+            endpoints_info.realloc(0, false)?;
+            msg!("Service endpoints closed");
+        }
 
-    /// CHECK: This is not dangerous because we are passing the right seeds
-    #[account(
-        mut,
-        seeds = [
-            b"metadata",
-            token_metadata_program.key().as_ref(),
-            mint.key().as_ref()
-        ],
-        bump,
-        seeds::program = token_metadata_program.key()
-    )]
-    pub metadata_account: UncheckedAccount<'info>,
+        let closed_at = Clock::get()?.unix_timestamp;
 
-    /// CHECK: This is not dangerous because we are passing the right seeds
-    #[account(
-        mut,
-        seeds = [
-            b"metadata",
-            token_metadata_program.key().as_ref(),
-            mint.key().as_ref(),
-            b"edition"
-        ],
-        bump,
-        seeds::program = token_metadata_program.key()
-    )]
-    pub master_edition_account: UncheckedAccount<'info>,
+        let username_tombstone = &mut ctx.accounts.username_tombstone;
+        username_tombstone.authority = ctx.accounts.authority.key();
+        username_tombstone.username = ctx.accounts.identity_account.username.clone();
+        username_tombstone.closed_at = closed_at;
+        username_tombstone.bump = ctx.bumps.username_tombstone;
 
-    // --- Required Programs ---
+        // The wallet may already have one from an earlier close or handover.
+        // It then records the latest one.
+        let tombstone = &mut ctx.accounts.tombstone;
+        tombstone.authority = ctx.accounts.authority.key();
+        tombstone.username = ctx.accounts.identity_account.username.clone();
+        tombstone.closed_at = closed_at;
+        tombstone.bump = ctx.bumps.tombstone;
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub token_metadata_program: Program<'info, Metadata>,
-    pub rent: Sysvar<'info, Rent>,
-}
+        msg!("Identity closed for {} with username: {}", tombstone.authority, tombstone.username);
+        if ctx.accounts.reputation_account.is_some() {
+            msg!("Reputation account closed");
+        }
 
-#[derive(Accounts)]
-pub struct InitializeReputation<'info> {
-    // The agent (authority) who is creating their reputation account
-    #[account(mut)]
-    pub authority: Signer<'info>,
+        Ok(())
+    }
 
-    // The identity account, used as a check to ensure the signer
-    // is a registered agent.
-    #[account(
-        seeds = [b"identity", authority.key().as_ref()],
-        bump = identity_account.bump,
-        // We constrain that the authority on the identity account
-        // matches the signer.
-        has_one = authority
-    )]
-    pub identity_account: Account<'info, IdentityAccount>,
+    // Owner only. Adds an operator key, or updates its permissions and expiry
+    // if it is already an operator.
+    pub fn add_operator(ctx: Context<ManageOperators>, operator: Pubkey, permissions: u8, expires_at: i64) -> Result<()> {
+        ctx.accounts.identity_account.set_operator(operator, permissions, expires_at)?;
 
-    // The new reputation account PDA
-    #[account(
-        init,
-        payer = authority,
-        // Space = 8 (disc) + 32 (auth) + 8 (txns) + 8 (vol) + 8 (reviews) + 8 (rating) + 1 (bump)
-        space = 8 + 32 + 8 + 8 + 8 + 8 + 1,
-        seeds = [b"reputation", authority.key().as_ref()],
-        bump
-    )]
-    pub reputation_account: Account<'info, ReputationAccount>,
+        msg!("Operator {} set with permissions {:#04x} until {}", operator, permissions, expires_at);
+        Ok(())
+    }
 
-    pub system_program: Program<'info, System>,
-}
+    // Owner only. Removes an operator key. AI agents must keep at least one.
+    pub fn revoke_operator(ctx: Context<ManageOperators>, operator: Pubkey) -> Result<()> {
+        let identity = &mut ctx.accounts.identity_account;
+        let index = identity.operators.iter().position(|op| op.key == operator)
+            .ok_or(ErrorCode::OperatorNotFound)?;
+        if identity.kind == IdentityKind::AiAgent && identity.operators.len() == 1 {
+            return err!(ErrorCode::OperatorRequired);
+        }
+        identity.operators.remove(index);
 
-#[derive(Accounts)]
-pub struct LogServiceTransaction<'info> {
-    // The user paying for the service
-    #[account(mut)]
-    pub payer: Signer<'info>,
+        msg!("Operator {} revoked", operator);
+        Ok(())
+    }
 
-    /// CHECK: This is the agent (identity) being paid.
-    /// We validate it by checking its relationship to the reputation_account.
-    pub authority: AccountInfo<'info>,
+    // Owner only. Kill switch: while suspended, operators can't act for the
+    // identity and the agent can't take service payments.
+    pub fn set_suspended(ctx: Context<ManageOperators>, suspended: bool) -> Result<()> {
+        ctx.accounts.identity_account.suspended = suspended;
 
-    // The agent's reputation account (to be updated)
-    #[account(
-        mut,
-        seeds = [b"reputation", authority.key().as_ref()],
-        bump = reputation_account.bump,
-        // Crucial check: ensures this reputation account
-        // belongs to the agent_authority we are paying.
-        has_one = authority
-    )]
-    pub reputation_account: Account<'info, ReputationAccount>,
+        msg!("Identity {} suspended: {}", ctx.accounts.identity_account.key(), suspended);
+        Ok(())
+    }
 
-    // The mint of the token being transferred (e.g., USDC)
-    pub mint: Account<'info, Mint>,
+    // Creates the (empty) list of service endpoints for an identity. The owner
+    // or an operator with `OPERATOR_MANAGE_ENDPOINTS` can manage the list.
+    pub fn initialize_endpoints(ctx: Context<InitializeEndpoints>) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_MANAGE_ENDPOINTS)?;
 
-    // The payer's token account (from which to send)
-    #[account(
-        mut,
-        associated_token::mint = mint,
-        associated_token::authority = payer,
-    )]
-    pub payer_token_account: Account<'info, TokenAccount>,
-    
-    // The agent's token account (to receive payment)
-    #[account(
-        mut,
-        associated_token::mint = mint,
-        associated_token::authority = authority,
-    )]
-    pub agent_token_account: Account<'info, TokenAccount>,
+        let endpoints = &mut ctx.accounts.endpoints;
+        endpoints.identity = ctx.accounts.identity_account.key();
+        endpoints.bump = ctx.bumps.endpoints;
 
-    // Required programs
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-}
+        msg!("Service endpoints created for {}", endpoints.identity);
+        Ok(())
+    }
+
+    // Appends a service endpoint. The account grows by one entry.
+    pub fn add_endpoint(ctx: Context<AddEndpoint>, protocol: EndpointProtocol, url: String, label: String) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_MANAGE_ENDPOINTS)?;
+        validate_endpoint(&url, &label)?;
+
+        let endpoints = &mut ctx.accounts.endpoints;
+        if endpoints.endpoints.len() >= MAX_ENDPOINTS {
+            return err!(ErrorCode::TooManyEndpoints);
+        }
+        endpoints.endpoints.push(ServiceEndpoint { protocol, url, label });
+
+        msg!("Endpoint {} added for {}", endpoints.endpoints.len() - 1, endpoints.identity);
+        Ok(())
+    }
+
+    // Replaces the endpoint at `index`
+    pub fn update_endpoint(ctx: Context<UpdateEndpoint>, index: u8, protocol: EndpointProtocol, url: String, label: String) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_MANAGE_ENDPOINTS)?;
+        validate_endpoint(&url, &label)?;
+
+        let endpoints = &mut ctx.accounts.endpoints;
+        let endpoint = endpoints.endpoints.get_mut(index as usize)
+            .ok_or(ErrorCode::EndpointNotFound)?;
+        *endpoint = ServiceEndpoint { protocol, url, label };
+
+        msg!("Endpoint {} updated for {}", index, endpoints.identity);
+        Ok(())
+    }
+
+    // Removes the endpoint at `index`, keeping the order of the others.
+    // The account shrinks by one entry and the rent goes back to the signer.
+    pub fn remove_endpoint(ctx: Context<RemoveEndpoint>, index: u8) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_MANAGE_ENDPOINTS)?;
+
+        let endpoints = &mut ctx.accounts.endpoints;
+        if index as usize >= endpoints.endpoints.len() {
+            return err!(ErrorCode::EndpointNotFound);
+        }
+        endpoints.endpoints.remove(index as usize);
+
+        msg!("Endpoint {} removed for {}", index, endpoints.identity);
+        Ok(())
+    }
+
+    // Adds a capability tag (e.g. "translation") to an identity and appends the
+    // identity to the tag's index. Clients enumerate a tag's identities by deriving
+    // `["tag_entry", tag_index, i]` for i in 0..count.
+    pub fn add_tag(ctx: Context<AddTag>, tag: String) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_UPDATE_METADATA)?;
+        let tag = validate_tag(&tag)?;
+
+        let identity = &mut ctx.accounts.identity_account;
+        if identity.tags.len() >= MAX_TAGS {
+            return err!(ErrorCode::TooManyTags);
+        }
+        identity.tags.push(tag.clone());
+
+        // The index is created by the first identity to use the tag
+        let tag_index = &mut ctx.accounts.tag_index;
+        if tag_index.tag.is_empty() {
+            tag_index.tag = tag;
+            tag_index.bump = ctx.bumps.tag_index;
+        }
+
+        let tag_entry = &mut ctx.accounts.tag_entry;
+        tag_entry.tag_index = tag_index.key();
+        tag_entry.identity = identity.key();
+        tag_entry.bump = ctx.bumps.tag_entry;
+
+        let tag_member = &mut ctx.accounts.tag_member;
+        tag_member.tag_index = tag_index.key();
+        tag_member.identity = identity.key();
+        tag_member.index = tag_index.count;
+        tag_member.bump = ctx.bumps.tag_member;
+
+        tag_index.count = tag_index.count.checked_add(1).ok_or(ErrorCode::Overflow)?;
+
+        msg!("Identity {} tagged '{}' ({} in the index)", identity.key(), tag_index.tag, tag_index.count);
+        Ok(())
+    }
+
+    // Removes a capability tag. The index stays dense: the last entry is moved
+    // into the removed slot and the last slot is closed.
+    pub fn remove_tag(ctx: Context<RemoveTag>, tag: String) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_UPDATE_METADATA)?;
+        let tag = normalize_username(&tag);
+
+        let identity = &mut ctx.accounts.identity_account;
+        let position = identity.tags.iter().position(|t| *t == tag)
+            .ok_or(ErrorCode::TagNotFound)?;
+        identity.tags.remove(position);
+
+        let removed = ctx.accounts.tag_member.index;
+        let last = ctx.accounts.tag_index.count.checked_sub(1)
+            .ok_or(ErrorCode::TagIndexEmpty)?;
+
+        // `last_entry` can only be derived once `last` is known to be valid
+        let last_entry_address = Pubkey::create_program_address(
+            &[
+                b"tag_entry",
+                ctx.accounts.tag_index.key().as_ref(),
+                last.to_le_bytes().as_ref(),
+                &[ctx.accounts.last_entry.bump],
+            ],
+            &crate::ID,
+        );
+        if last_entry_address != Ok(ctx.accounts.last_entry.key()) {
+            return Err(error!(anchor_lang::error::ErrorCode::ConstraintSeeds).with_account_name("last_entry"));
+        }
+
+        if removed != last {
+            // Both are required unless the removed entry is already the last one
+            let (Some(entry), Some(last_member)) = (ctx.accounts.entry.as_mut(), ctx.accounts.last_member.as_mut()) else {
+                return err!(ErrorCode::TagAccountsMissing);
+            };
+            entry.identity = ctx.accounts.last_entry.identity;
+            last_member.index = removed;
+        }
+
+        // `tag_member` and `last_entry` are closed by their `close` constraints
+        ctx.accounts.tag_index.count = last;
+
+        msg!("Identity {} untagged '{}' ({} left in the index)", identity.key(), tag, last);
+        Ok(())
+    }
+
+    // Lists a service the agent sells. `service_id` is chosen by the agent and
+    // is unique per identity. Set `min_price == max_price` for a fixed price.
+    pub fn create_service(
+        ctx: Context<CreateService>,
+        service_id: u32,
+        uri: String,
+        min_price: u64,
+        max_price: u64,
+    ) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_MANAGE_SERVICES)?;
+        validate_uri(&uri)?;
+        validate_price_range(min_price, max_price)?;
+
+        let service = &mut ctx.accounts.service;
+        service.identity = ctx.accounts.identity_account.key();
+        service.service_id = service_id;
+        service.uri = uri;
+        service.mint = ctx.accounts.mint.key();
+        service.min_price = min_price;
+        service.max_price = max_price;
+        service.active = true;
+        service.bump = ctx.bumps.service;
+
+        let identity = &mut ctx.accounts.identity_account;
+        identity.service_count = identity.service_count.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!("Service {} listed for {}", service_id, service.identity);
+        msg!("  Mint: {}", service.mint);
+        msg!("  Price: {} - {}", min_price, max_price);
+        Ok(())
+    }
+
+    // Changes a listing's description and price, or pauses it. The accepted
+    // mint is fixed; list a new service to accept another one.
+    pub fn update_service(
+        ctx: Context<ManageService>,
+        uri: String,
+        min_price: u64,
+        max_price: u64,
+        active: bool,
+    ) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_MANAGE_SERVICES)?;
+        validate_uri(&uri)?;
+        validate_price_range(min_price, max_price)?;
+
+        let service = &mut ctx.accounts.service;
+        service.uri = uri;
+        service.min_price = min_price;
+        service.max_price = max_price;
+        service.active = active;
+
+        msg!("Service {} updated for {} (active: {})", service.service_id, service.identity, active);
+        Ok(())
+    }
+
+    // Removes a listing. The rent goes back to the signer.
+    pub fn close_service(ctx: Context<CloseService>) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_MANAGE_SERVICES)?;
+
+        let identity = &mut ctx.accounts.identity_account;
+        identity.service_count = identity.service_count.checked_sub(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!("Service {} closed for {}", ctx.accounts.service.service_id, ctx.accounts.service.identity);
+        Ok(())
+    }
+
+    // Accepts payments in `mint`, between `min_amount` and `max_amount` per
+    // payment (inclusive). Calling it again updates the bounds. Payments in a
+    // mint without a policy are rejected.
+    pub fn set_payment_policy(ctx: Context<SetPaymentPolicy>, min_amount: u64, max_amount: u64) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_MANAGE_SERVICES)?;
+        validate_price_range(min_amount, max_amount)?;
+
+        // A new policy still has the default identity
+        if ctx.accounts.payment_policy.identity == Pubkey::default() {
+            let identity = &mut ctx.accounts.identity_account;
+            identity.payment_policy_count = identity.payment_policy_count.checked_add(1)
+                .ok_or(ErrorCode::Overflow)?;
+        }
+
+        let policy = &mut ctx.accounts.payment_policy;
+        policy.identity = ctx.accounts.identity_account.key();
+        policy.mint = ctx.accounts.mint.key();
+        policy.min_amount = min_amount;
+        policy.max_amount = max_amount;
+        policy.bump = ctx.bumps.payment_policy;
+
+        msg!("Identity {} accepts {} ({} - {})", policy.identity, policy.mint, min_amount, max_amount);
+        Ok(())
+    }
+
+    // Stops accepting payments in the policy's mint. The rent goes back to the signer.
+    pub fn remove_payment_policy(ctx: Context<RemovePaymentPolicy>) -> Result<()> {
+        ctx.accounts.identity_account.authorize(&ctx.accounts.signer.key(), OPERATOR_MANAGE_SERVICES)?;
+
+        let identity = &mut ctx.accounts.identity_account;
+        identity.payment_policy_count = identity.payment_policy_count.checked_sub(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!("Identity {} no longer accepts {}", ctx.accounts.payment_policy.identity, ctx.accounts.payment_policy.mint);
+        Ok(())
+    }
+
+    // Succeeds only if the signer holds the identity NFT. Other programs can
+    // CPI into this, or embed `IdentityHolder` in their own accounts.
+    pub fn verify_identity(ctx: Context<VerifyIdentity>) -> Result<()> {
+        msg!(
+            "{} holds identity {} ({})",
+            ctx.accounts.identity_holder.holder.key(),
+            ctx.accounts.identity_holder.identity_account.key(),
+            ctx.accounts.identity_holder.identity_account.username
+        );
+        Ok(())
+    }
+
+    // Admin only. Sets the timelock applied to new authority change proposals.
+    pub fn set_authority_change_delay(ctx: Context<SetAuthorityChangeDelay>, delay: i64) -> Result<()> {
+        if delay < 0 {
+            return err!(ErrorCode::InvalidAuthorityChangeDelay);
+        }
+
+        ctx.accounts.config.authority_change_delay = delay;
+
+        msg!("Authority change delay set to {} seconds", delay);
+        Ok(())
+    }
+
+    // Admin only. Counts payments in `mint` towards reputation aggregates.
+    // Canonical mints should share a unit (e.g. 6-decimal USD stablecoins),
+    // since their volumes are summed.
+    pub fn add_canonical_mint(ctx: Context<ManageCanonicalMints>, mint: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        if config.canonical_mints.contains(&mint) {
+            msg!("Mint {} is already canonical", mint);
+            return Ok(());
+        }
+        if config.canonical_mints.len() >= MAX_CANONICAL_MINTS {
+            return err!(ErrorCode::TooManyCanonicalMints);
+        }
+        config.canonical_mints.push(mint);
+
+        msg!("Mint {} is now canonical", mint);
+        Ok(())
+    }
+
+    // Admin only. Payments in `mint` stop counting towards reputation
+    // aggregates. Volumes already counted stay.
+    pub fn remove_canonical_mint(ctx: Context<ManageCanonicalMints>, mint: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let index = config.canonical_mints.iter().position(|m| *m == mint)
+            .ok_or(ErrorCode::CanonicalMintNotFound)?;
+        config.canonical_mints.remove(index);
+
+        msg!("Mint {} is no longer canonical", mint);
+        Ok(())
+    }
+
+    // Step 1 of an authority change. The current authority proposes a new key,
+    // which can accept once the registry's timelock has passed.
+    pub fn propose_authority_change(ctx: Context<ProposeAuthorityChange>, new_authority: Pubkey) -> Result<()> {
+        if new_authority == ctx.accounts.authority.key() {
+            return err!(ErrorCode::AuthorityUnchanged);
+        }
+
+        let pending = &mut ctx.accounts.pending_authority_change;
+        pending.identity = ctx.accounts.identity_account.key();
+        pending.current_authority = ctx.accounts.authority.key();
+        pending.new_authority = new_authority;
+        pending.unlock_at = Clock::get()?.unix_timestamp
+            .checked_add(ctx.accounts.config.authority_change_delay)
+            .ok_or(ErrorCode::Overflow)?;
+        pending.bump = ctx.bumps.pending_authority_change;
+
+        msg!("Authority change proposed from {} to {}", pending.current_authority, pending.new_authority);
+        msg!("  Unlocks at: {}", pending.unlock_at);
+        Ok(())
+    }
+
+    // Lets the current authority withdraw a pending authority change
+    pub fn cancel_authority_change(ctx: Context<CancelAuthorityChange>) -> Result<()> {
+        msg!(
+            "Authority change to {} cancelled",
+            ctx.accounts.pending_authority_change.new_authority
+        );
+        Ok(())
+    }
+
+    // Step 2 of an authority change. The new authority accepts and takes over
+    // the identity in place. The identity PDA is seeded by its creator, so its
+    // address, and everything keyed by it, stays the same. The old owner's
+    // operators and kill switch don't carry over. `operator` becomes the first
+    // operator, and AI agents must pass one. The old key is tombstoned so it
+    // can't register a fresh identity.
+    pub fn accept_authority_change(ctx: Context<AcceptAuthorityChange>, operator: Option<Operator>) -> Result<()> {
+        if Clock::get()?.unix_timestamp < ctx.accounts.pending_authority_change.unlock_at {
+            return err!(ErrorCode::AuthorityChangeTimelocked);
+        }
+
+        let old_authority = ctx.accounts.old_authority.key();
+        let new_authority = ctx.accounts.new_authority.key();
+
+        // A program identity stays with a program, the same rule as at registration
+        if ctx.accounts.identity_account.kind == IdentityKind::Program && is_on_curve(&new_authority) {
+            return err!(ErrorCode::ProgramIdentityRequiresCpi);
+        }
+
+        // 1. Hand the identity and its reputation to the new authority
+        let identity = &mut ctx.accounts.identity_account;
+        identity.authority = new_authority;
+        identity.suspended = false;
+        identity.operators.clear();
+        match operator {
+            Some(operator) => identity.set_operator(operator.key, operator.permissions, operator.expires_at)?,
+            None if identity.kind == IdentityKind::AiAgent => return err!(ErrorCode::OperatorRequired),
+            None => {}
+        }
+
+        // The reputation account is optional, but its address is fixed, so the
+        // caller can't skip it to leave a stale authority behind.
+        let reputation_info = ctx.accounts.reputation_account.to_account_info();
+        if !reputation_info.data_is_empty() && reputation_info.owner == &crate::ID {
+            let mut reputation_account = ReputationAccount::try_deserialize(&mut &reputation_info.try_borrow_data()?[..])?;
+            reputation_account.authority = new_authority;
+            reputation_account.try_serialize(&mut &mut reputation_info.try_borrow_mut_data()?[..])?;
+        }
+
+        // 2. Point the lookups at the new authority
+        ctx.accounts.username_claim.authority = new_authority;
+        ctx.accounts.identity_mint_record.authority = new_authority;
+
+        let identity = &ctx.accounts.identity_account;
+        let has_display_name_claim = display_name_key(&identity.display_name) != identity.username;
+        match ctx.accounts.display_name_claim.as_mut() {
+            Some(claim) if has_display_name_claim => claim.authority = new_authority,
+            None if !has_display_name_claim => {}
+            _ => return err!(ErrorCode::DisplayNameClaimMismatch),
+        }
+
+        // 3. Move the identity to the new authority's list
+        let counter = &mut ctx.accounts.new_identity_counter;
+        counter.authority = new_authority;
+        counter.bump = ctx.bumps.new_identity_counter;
+        let owned_index = counter.next_owned_slot()?;
+
+        let owned_identity = &mut ctx.accounts.new_owned_identity;
+        owned_identity.authority = new_authority;
+        owned_identity.identity = ctx.accounts.identity_account.key();
+        owned_identity.bump = ctx.bumps.new_owned_identity;
+        ctx.accounts.identity_account.owned_index = owned_index;
+
+        // 4. Block the old key from registering a fresh identity
+        let tombstone = &mut ctx.accounts.old_tombstone;
+        tombstone.authority = old_authority;
+        tombstone.username = ctx.accounts.identity_account.username.clone();
+        tombstone.closed_at = Clock::get()?.unix_timestamp;
+        tombstone.bump = ctx.bumps.old_tombstone;
+
+        // 5. Move the soulbound NFT. The registry PDA is the token delegate and
+        // close authority, so it can thaw the old token account, transfer the NFT
+        // out and close the emptied account without the old key.
+        let registry_authority_seeds: &[&[&[u8]]] = &[&[b"registry_authority", &[ctx.bumps.registry_authority]]];
+
+        thaw_delegated_account(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                ThawDelegatedAccount {
+                    metadata: ctx.accounts.metadata_account.to_account_info(),
+                    delegate: ctx.accounts.registry_authority.to_account_info(),
+                    token_account: ctx.accounts.old_token_account.to_account_info(),
+                    edition: ctx.accounts.master_edition_account.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                },
+                registry_authority_seeds,
+            ),
+        )?;
+
+        transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.old_token_account.to_account_info(),
+                    to: ctx.accounts.new_token_account.to_account_info(),
+                    authority: ctx.accounts.registry_authority.to_account_info(),
+                },
+                registry_authority_seeds,
+            ),
+            1,
+        )?;
+
+        // The old owner gets the token account's rent back
+        close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.old_token_account.to_account_info(),
+                destination: ctx.accounts.old_authority.to_account_info(),
+                authority: ctx.accounts.registry_authority.to_account_info(),
+            },
+            registry_authority_seeds,
+        ))?;
+
+        approve(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Approve {
+                    to: ctx.accounts.new_token_account.to_account_info(),
+                    delegate: ctx.accounts.registry_authority.to_account_info(),
+                    authority: ctx.accounts.new_authority.to_account_info(),
+                },
+            ),
+            1,
+        )?;
+
+        set_authority(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                SetAuthority {
+                    current_authority: ctx.accounts.new_authority.to_account_info(),
+                    account_or_mint: ctx.accounts.new_token_account.to_account_info(),
+                },
+            ),
+            AuthorityType::CloseAccount,
+            Some(ctx.accounts.registry_authority.key()),
+        )?;
+
+        freeze_delegated_account(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                FreezeDelegatedAccount {
+                    metadata: ctx.accounts.metadata_account.to_account_info(),
+                    delegate: ctx.accounts.registry_authority.to_account_info(),
+                    token_account: ctx.accounts.new_token_account.to_account_info(),
+                    edition: ctx.accounts.master_edition_account.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                },
+                registry_authority_seeds,
+            ),
+        )?;
+
+        msg!("Authority changed from {} to {}", old_authority, new_authority);
+        Ok(())
+    }
+
+    // Initialize a reputation account for an existing identity
+    pub fn initialize_reputation(ctx: Context<InitializeReputation>) -> Result<()> {
+        let reputation_account = &mut ctx.accounts.reputation_account;
+        
+        reputation_account.authority = ctx.accounts.authority.key();
+        reputation_account.total_transactions = 0;
+        reputation_account.total_volume = 0;
+        reputation_account.total_reviews = 0;
+        reputation_account.total_rating_score = 0;
+        reputation_account.bump = ctx.bumps.reputation_account;
+
+        msg!("Reputation account created for authority {}", reputation_account.authority);
+        Ok(())
+    }
+
+    pub fn log_service_transaction(ctx: Context<LogServiceTransaction>, amount: u64) -> Result<()> {
+        // SPL allows zero-amount transfers, which would otherwise create a
+        // payment record (and so the right to review) for free
+        if amount == 0 {
+            return err!(ErrorCode::ZeroPaymentAmount);
+        }
+
+        // 1. The agent must accept the mint, and the amount must be within its bounds.
+        // A listing is the agent's own rule for its mint and price, so a payment
+        // for a listed service must match the listing and needs no payment policy.
+        // The `service` constraints already checked the identity, mint and status.
+        if let Some(service) = ctx.accounts.service.as_mut() {
+            if amount < service.min_price || amount > service.max_price {
+                return err!(ErrorCode::PriceOutOfRange);
+            }
+
+            service.total_transactions = service.total_transactions.checked_add(1)
+                .ok_or(ErrorCode::Overflow)?;
+            service.total_volume = service.total_volume.checked_add(amount)
+                .ok_or(ErrorCode::Overflow)?;
+
+            msg!("Payment for service {}", service.service_id);
+        } else {
+            let policy = ctx.accounts.payment_policy.as_ref().ok_or(ErrorCode::MintNotAccepted)?;
+            if amount < policy.min_amount {
+                return err!(ErrorCode::PaymentBelowMinimum);
+            }
+            if amount > policy.max_amount {
+                return err!(ErrorCode::PaymentAboveMaximum);
+            }
+        }
+
+        // 2. CPI to transfer tokens from payer to agent
+        msg!("Transferring {} tokens from payer to agent", amount);
+        
+        // We use the `transfer` function from the `anchor_spl::token` crate
+        transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.payer_token_account.to_account_info(),
+                    to: ctx.accounts.agent_token_account.to_account_info(),
+                    authority: ctx.accounts.payer.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        msg!("Transfer complete");
+
+        // 3. Update the agent's volume in this mint
+        let mint_volume = &mut ctx.accounts.mint_volume;
+        mint_volume.identity = ctx.accounts.identity_account.key();
+        mint_volume.mint = ctx.accounts.mint.key();
+        mint_volume.bump = ctx.bumps.mint_volume;
+
+        // Use checked_add to prevent overflow
+        mint_volume.total_transactions = mint_volume.total_transactions.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        mint_volume.total_volume = mint_volume.total_volume.checked_add(amount)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!("Volume updated for {} in {}", mint_volume.identity, mint_volume.mint);
+        msg!("  Mint Transactions: {}", mint_volume.total_transactions);
+        msg!("  Mint Volume: {}", mint_volume.total_volume);
+
+        // 4. Update the agent's reputation account. Only canonical mints count,
+        // so the aggregate volume is in a single unit.
+        let reputation_account = &mut ctx.accounts.reputation_account;
+        if ctx.accounts.config.canonical_mints.contains(&ctx.accounts.mint.key()) {
+            reputation_account.total_transactions = reputation_account.total_transactions.checked_add(1)
+                .ok_or(ErrorCode::Overflow)?;
+
+            reputation_account.total_volume = reputation_account.total_volume.checked_add(amount)
+                .ok_or(ErrorCode::Overflow)?;
+
+            msg!("Reputation updated for {}", reputation_account.authority);
+            msg!("  Total Transactions: {}", reputation_account.total_transactions);
+            msg!("  Total Volume: {}", reputation_account.total_volume);
+        }
+
+        // 5. Record the payment for this payer/agent pair. This is the proof
+        // of purchase that `submit_review` checks before accepting a review.
+        let payment_record = &mut ctx.accounts.payment_record;
+        payment_record.agent_authority = ctx.accounts.authority.key();
+        payment_record.payer = ctx.accounts.payer.key();
+        payment_record.bump = ctx.bumps.payment_record;
+
+        payment_record.total_transactions = payment_record.total_transactions.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        payment_record.total_amount = payment_record.total_amount.checked_add(amount)
+            .ok_or(ErrorCode::Overflow)?;
+
+        // What the agent advertised when it was paid. Earlier versions are
+        // archived, so this can be looked up after the profile changes.
+        payment_record.last_metadata_version = ctx.accounts.identity_account.metadata_version;
+        msg!("  Metadata version: {}", payment_record.last_metadata_version);
+
+        Ok(())
+    }
+
+    // Leave a review for an agent. Each reviewer can review a given agent once.
+    pub fn submit_review(ctx: Context<SubmitReview>, rating: u8, uri: String) -> Result<()> {
+        if !(1..=5).contains(&rating) {
+            return err!(ErrorCode::InvalidRating);
+        }
+
+        validate_uri(&uri)?;
+
+        // Only reviewers who actually paid the agent may review it. A missing
+        // record is reported as `NoPaidTransaction`, not as an uninitialized account.
+        let payment_record = &ctx.accounts.payment_record;
+        if payment_record.data_is_empty() || payment_record.owner != &crate::ID {
+            return err!(ErrorCode::NoPaidTransaction);
+        }
+        let payment_record = PaymentRecord::try_deserialize(&mut &payment_record.try_borrow_data()?[..])?;
+        if payment_record.total_transactions == 0 || payment_record.total_amount == 0 {
+            return err!(ErrorCode::NoPaidTransaction);
+        }
+
+        let review = &mut ctx.accounts.review_account;
+        review.agent_authority = ctx.accounts.authority.key();
+        review.reviewer = ctx.accounts.reviewer.key();
+        review.rating = rating;
+        review.uri = uri;
+        review.bump = ctx.bumps.review_account;
+
+        // Update the agent's aggregate rating in the same instruction,
+        // so the review and the reputation can never get out of sync.
+        let reputation_account = &mut ctx.accounts.reputation_account;
+
+        reputation_account.total_reviews = reputation_account.total_reviews.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        reputation_account.total_rating_score = reputation_account.total_rating_score.checked_add(rating as u64)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!("Review submitted by {} for {} with rating {}", review.reviewer, review.agent_authority, rating);
+        msg!("  Total Reviews: {}", reputation_account.total_reviews);
+        msg!("  Total Rating Score: {}", reputation_account.total_rating_score);
+
+        Ok(())
+    }
+
+    // Creates an organization that agents can join under a shared brand.
+    // The creator's identity becomes its first admin.
+    pub fn create_organization(ctx: Context<CreateOrganization>, name: String, uri: String) -> Result<()> {
+        // Organization names follow the username rules, in their own namespace
+        let name = validate_username(&name)?;
+        validate_uri(&uri)?;
+
+        let organization = &mut ctx.accounts.organization;
+        organization.creator = ctx.accounts.authority.key();
+        organization.name = name;
+        organization.uri = uri;
+        organization.member_count = 1;
+        organization.admin_count = 1;
+        organization.bump = ctx.bumps.organization;
+
+        let membership = &mut ctx.accounts.membership;
+        membership.organization = organization.key();
+        membership.identity = ctx.accounts.identity_account.key();
+        membership.authority = ctx.accounts.authority.key();
+        membership.role = OrganizationRole::Admin;
+        membership.joined_at = Clock::get()?.unix_timestamp;
+        membership.bump = ctx.bumps.membership;
+
+        let identity = &mut ctx.accounts.identity_account;
+        identity.org_membership_count = identity.org_membership_count.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!("Organization {} created by {}", organization.name, membership.identity);
+        Ok(())
+    }
+
+    // Adds an identity to an organization. Both the identity's authority and
+    // an organization admin must sign, so neither side can be added unilaterally.
+    pub fn join_organization(ctx: Context<JoinOrganization>, role: OrganizationRole) -> Result<()> {
+        let organization = &mut ctx.accounts.organization;
+        organization.member_count = organization.member_count.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+        if role == OrganizationRole::Admin {
+            organization.admin_count = organization.admin_count.checked_add(1)
+                .ok_or(ErrorCode::Overflow)?;
+        }
+
+        let membership = &mut ctx.accounts.membership;
+        membership.organization = organization.key();
+        membership.identity = ctx.accounts.identity_account.key();
+        membership.authority = ctx.accounts.authority.key();
+        membership.role = role;
+        membership.joined_at = Clock::get()?.unix_timestamp;
+        membership.bump = ctx.bumps.membership;
+
+        let identity = &mut ctx.accounts.identity_account;
+        identity.org_membership_count = identity.org_membership_count.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!("{} joined organization {}", membership.identity, organization.name);
+        Ok(())
+    }
+
+    // Admin only. Removes a member, and takes its reputation back out of the
+    // organization's aggregate. The last admin can't be removed. The rent goes
+    // to the member identity's current authority.
+    pub fn remove_member(ctx: Context<RemoveMember>) -> Result<()> {
+        let organization = &mut ctx.accounts.organization;
+        let membership = &ctx.accounts.membership;
+        organization.release_membership(membership)?;
+
+        let identity = &mut ctx.accounts.member_identity;
+        identity.org_membership_count = identity.org_membership_count.checked_sub(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!("{} removed from organization {}", membership.identity, organization.name);
+        Ok(())
+    }
+
+    // Member only. Lets an identity's current authority end its own
+    // membership, with the same bookkeeping as `remove_member`. The last
+    // admin can't leave.
+    pub fn leave_organization(ctx: Context<LeaveOrganization>) -> Result<()> {
+        let organization = &mut ctx.accounts.organization;
+        let membership = &ctx.accounts.membership;
+        organization.release_membership(membership)?;
+
+        let identity = &mut ctx.accounts.identity_account;
+        identity.org_membership_count = identity.org_membership_count.checked_sub(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        msg!("{} left organization {}", membership.identity, organization.name);
+        Ok(())
+    }
+
+    // Permissionless. Adds whatever a member's reputation gained since the
+    // last sync to the organization's aggregate reputation.
+    pub fn sync_member_reputation(ctx: Context<SyncMemberReputation>) -> Result<()> {
+        let reputation = &ctx.accounts.reputation_account;
+        let membership = &mut ctx.accounts.membership;
+        let organization = &mut ctx.accounts.organization;
+
+        let new_transactions = reputation.total_transactions.checked_sub(membership.synced_transactions)
+            .ok_or(ErrorCode::Overflow)?;
+        let new_volume = reputation.total_volume.checked_sub(membership.synced_volume)
+            .ok_or(ErrorCode::Overflow)?;
+        let new_reviews = reputation.total_reviews.checked_sub(membership.synced_reviews)
+            .ok_or(ErrorCode::Overflow)?;
+        let new_rating_score = reputation.total_rating_score.checked_sub(membership.synced_rating_score)
+            .ok_or(ErrorCode::Overflow)?;
+
+        organization.total_transactions = organization.total_transactions.checked_add(new_transactions)
+            .ok_or(ErrorCode::Overflow)?;
+        organization.total_volume = organization.total_volume.checked_add(new_volume)
+            .ok_or(ErrorCode::Overflow)?;
+        organization.total_reviews = organization.total_reviews.checked_add(new_reviews)
+            .ok_or(ErrorCode::Overflow)?;
+        organization.total_rating_score = organization.total_rating_score.checked_add(new_rating_score)
+            .ok_or(ErrorCode::Overflow)?;
+
+        membership.synced_transactions = reputation.total_transactions;
+        membership.synced_volume = reputation.total_volume;
+        membership.synced_reviews = reputation.total_reviews;
+        membership.synced_rating_score = reputation.total_rating_score;
+
+        msg!("Reputation of {} synced to organization {}", membership.identity, organization.name);
+        msg!("  Total Transactions: {}", organization.total_transactions);
+        msg!("  Total Volume: {}", organization.total_volume);
+        Ok(())
+    }
+
+    // Succeeds only if the signer holds the NFT of an identity that is a
+    // member of the organization. Like `verify_identity`, meant for CPI.
+    pub fn verify_membership(ctx: Context<VerifyMembership>) -> Result<()> {
+        msg!(
+            "{} is a {:?} of organization {}",
+            ctx.accounts.identity_holder.identity_account.key(),
+            ctx.accounts.membership.role,
+            ctx.accounts.organization.name
+        );
+        Ok(())
+    }
+}
+
+// Limits match the Metaplex Token Metadata limits, since the username, symbol
+// and URI are passed straight through to the identity NFT's `DataV2`.
+pub const MAX_USERNAME_LENGTH: usize = mpl_token_metadata::MAX_NAME_LENGTH;
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_SYMBOL_LENGTH: usize = mpl_token_metadata::MAX_SYMBOL_LENGTH;
+pub const MAX_URI_LENGTH: usize = mpl_token_metadata::MAX_URI_LENGTH;
+
+// Operator permission flags. The owner (the identity's `authority`) always has all of them.
+pub const OPERATOR_UPDATE_METADATA: u8 = 1 << 0;
+pub const OPERATOR_MANAGE_SUB_IDENTITIES: u8 = 1 << 1;
+pub const OPERATOR_MANAGE_ENDPOINTS: u8 = 1 << 2;
+pub const OPERATOR_MANAGE_SERVICES: u8 = 1 << 3;
+pub const OPERATOR_ALL_PERMISSIONS: u8 =
+    OPERATOR_UPDATE_METADATA | OPERATOR_MANAGE_SUB_IDENTITIES | OPERATOR_MANAGE_ENDPOINTS | OPERATOR_MANAGE_SERVICES;
+
+// Maximum number of operator keys per identity
+pub const MAX_OPERATORS: usize = 4;
+
+// Maximum number of service endpoints per identity, and the length of an endpoint label
+pub const MAX_ENDPOINTS: usize = 8;
+pub const MAX_ENDPOINT_LABEL_LENGTH: usize = 32;
+
+// Maximum number of capability tags per identity, and the length of a tag
+pub const MAX_TAGS: usize = 8;
+pub const MAX_TAG_LENGTH: usize = 32;
+
+// How far below a root identity sub-identities can be nested
+pub const MAX_SUB_IDENTITY_DEPTH: u8 = 4;
+
+// Default timelock for authority changes (2 days), adjustable by the admin
+pub const DEFAULT_AUTHORITY_CHANGE_DELAY: i64 = 2 * 24 * 60 * 60;
+
+// Maximum number of mints counted towards reputation aggregates
+pub const MAX_CANONICAL_MINTS: usize = 4;
+
+// Names that could be used to impersonate the registry or its operators
+pub const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "identity",
+    "metaplex",
+    "moderator",
+    "official",
+    "registry",
+    "root",
+    "solana",
+    "support",
+    "system",
+];
+
+// Usernames are compared case-insensitively and without surrounding whitespace.
+// Fullwidth ASCII forms (e.g. "ａｌｉｃｅ") are folded to plain ASCII, like NFKC would,
+// so they can't be used to register a lookalike of an existing name.
+pub fn normalize_username(username: &str) -> String {
+    username
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+// The claim PDA is derived before the username is validated, so it is seeded by
+// the SHA-256 hash of the normalized username to stay within the 32 byte seed limit.
+pub fn username_seed(username: &str) -> [u8; 32] {
+    hash(normalize_username(username).as_bytes()).to_bytes()
+}
+
+// Normalizes and validates a username, returning the normalized form.
+// Allowed characters are lowercase ASCII letters, digits and underscores.
+pub fn validate_username(username: &str) -> Result<String> {
+    let username = normalize_username(username);
+
+    if username.len() > MAX_USERNAME_LENGTH {
+        return err!(ErrorCode::UsernameTooLong);
+    }
+
+    if username.len() < MIN_USERNAME_LENGTH {
+        return err!(ErrorCode::UsernameTooShort);
+    }
+
+    if !username.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+        return err!(ErrorCode::InvalidUsernameCharacter);
+    }
+
+    check_reserved_words(&username)?;
+
+    Ok(username)
+}
+
+// The one reserved-name rule for usernames and display names: no '_'-separated
+// word may be reserved, so "official_registry" and "support_bot" are rejected too
+pub fn check_reserved_words(name: &str) -> Result<()> {
+    if name.split('_').any(|word| RESERVED_USERNAMES.contains(&word)) {
+        return err!(ErrorCode::ReservedUsername);
+    }
+
+    Ok(())
+}
+
+// Symbols are short uppercase tickers, e.g. "IDENTITY"
+pub fn validate_symbol(symbol: &str) -> Result<()> {
+    if symbol.len() > MAX_SYMBOL_LENGTH {
+        return err!(ErrorCode::SymbolTooLong);
+    }
+
+    if symbol.is_empty() || !symbol.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+        return err!(ErrorCode::InvalidSymbol);
+    }
+
+    Ok(())
+}
+
+// Display names are free-form, but must fit in the Metaplex name field
+pub fn validate_display_name(display_name: &str) -> Result<String> {
+    let display_name = display_name.trim();
+
+    if display_name.len() > MAX_USERNAME_LENGTH {
+        return err!(ErrorCode::DisplayNameTooLong);
+    }
+
+    if display_name.is_empty() || display_name.chars().any(|c| c.is_control()) {
+        return err!(ErrorCode::InvalidDisplayName);
+    }
+
+    Ok(display_name.to_string())
+}
+
+// The form a display name is compared in: normalized like a username, with
+// whitespace, '-' and '.' turned into '_', e.g. "Official Registry" -> "official_registry"
+pub fn display_name_key(display_name: &str) -> String {
+    normalize_username(display_name)
+        .chars()
+        .map(|c| if c.is_whitespace() || c == '-' || c == '.' { '_' } else { c })
+        .collect()
+}
+
+// Seeds the claim reserving a display name. Display names share the username
+// namespace, so a name can't be one identity's username and another's display name.
+pub fn display_name_seed(display_name: &str) -> [u8; 32] {
+    username_seed(&display_name_key(display_name))
+}
+
+// Normalizes and validates a capability tag, returning the normalized form.
+// Tags use the username alphabet plus hyphens, e.g. "text-to-speech".
+pub fn validate_tag(tag: &str) -> Result<String> {
+    let tag = normalize_username(tag);
+
+    if tag.is_empty() || tag.len() > MAX_TAG_LENGTH {
+        return err!(ErrorCode::InvalidTag);
+    }
+
+    if !tag.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-') {
+        return err!(ErrorCode::InvalidTag);
+    }
+
+    Ok(tag)
+}
+
+// A listing's price range must be non-empty and allow a non-zero payment
+pub fn validate_price_range(min_price: u64, max_price: u64) -> Result<()> {
+    if max_price == 0 || min_price > max_price {
+        return err!(ErrorCode::InvalidPriceRange);
+    }
+
+    Ok(())
+}
+
+// Endpoint URLs share the URI limit. Labels are short, human-readable names.
+pub fn validate_endpoint(url: &str, label: &str) -> Result<()> {
+    validate_uri(url)?;
+
+    if url.is_empty() || label.is_empty() || label.len() > MAX_ENDPOINT_LABEL_LENGTH || label.chars().any(|c| c.is_control()) {
+        return err!(ErrorCode::InvalidEndpoint);
+    }
+
+    Ok(())
+}
+
+pub fn validate_uri(uri: &str) -> Result<()> {
+    if uri.len() > MAX_URI_LENGTH {
+        return err!(ErrorCode::UriTooLong);
+    }
+
+    Ok(())
+}
+
+// Whether `key` is a point on the ed25519 curve, i.e. could be a wallet key.
+// PDAs never are. `Pubkey::is_on_curve` is only available off-chain, so the
+// program asks the curve syscall instead.
+pub fn is_on_curve(key: &Pubkey) -> bool {
+    #[cfg(target_os = "solana")]
+    {
+        // Curve id 0 is ed25519. The syscall returns 0 for a valid point.
+        let mut result = 0u8;
+        unsafe {
+            anchor_lang::solana_program::syscalls::sol_curve_validate_point(0, key.as_ref().as_ptr(), &mut result) == 0
+        }
+    }
+    #[cfg(not(target_os = "solana"))]
+    {
+        key.is_on_curve()
+    }
+}
+
+// Custom program errors
+#[error_code]
+pub enum ErrorCode {
+    #[msg("Username is too long (max 32 characters)")]
+    UsernameTooLong,
+
+    #[msg("URI is too long (max 200 characters)")]
+    UriTooLong,
+
+    #[msg("Arithmetic overflow")]
+    Overflow,
+
+    #[msg("Rating must be between 1 and 5")]
+    InvalidRating,
+
+    #[msg("Agents cannot review themselves")]
+    SelfReview,
+
+    #[msg("Reviewer has no paid service transaction with this agent")]
+    NoPaidTransaction,
+
+    #[msg("Username is already taken")]
+    UsernameTaken,
+
+    #[msg("Username is too short (min 3 characters)")]
+    UsernameTooShort,
+
+    #[msg("Username may only contain lowercase letters, digits and underscores")]
+    InvalidUsernameCharacter,
+
+    #[msg("Username is reserved")]
+    ReservedUsername,
+
+    #[msg("Symbol is too long (max 10 characters)")]
+    SymbolTooLong,
+
+    #[msg("Symbol may only contain uppercase letters and digits")]
+    InvalidSymbol,
+
+    #[msg("Display name is too long (max 32 characters)")]
+    DisplayNameTooLong,
+
+    #[msg("Display name must not be empty or contain control characters")]
+    InvalidDisplayName,
+
+    #[msg("Signer does not hold the identity NFT")]
+    NotIdentityHolder,
+
+    #[msg("This wallet closed or handed over an identity and cannot register again")]
+    IdentityClosed,
+
+    #[msg("Signer is not the registry admin")]
+    Unauthorized,
+
+    #[msg("Authority change delay must not be negative")]
+    InvalidAuthorityChangeDelay,
+
+    #[msg("New authority must differ from the current authority")]
+    AuthorityUnchanged,
+
+    #[msg("Authority change is still timelocked")]
+    AuthorityChangeTimelocked,
+
+    #[msg("Cancel the pending authority change before closing the identity")]
+    AuthorityChangePending,
+
+    #[msg("Signer is not the owner or a permitted operator of this identity")]
+    OperatorNotAuthorized,
+
+    #[msg("Operator key has expired")]
+    OperatorExpired,
+
+    #[msg("Identity is suspended")]
+    IdentitySuspended,
+
+    #[msg("Too many operators (max 4)")]
+    TooManyOperators,
+
+    #[msg("Operator not found")]
+    OperatorNotFound,
+
+    #[msg("Invalid operator permissions")]
+    InvalidOperatorPermissions,
+
+    #[msg("Operator expiry must be in the future")]
+    InvalidOperatorExpiry,
+
+    #[msg("Signer is not an admin of this organization")]
+    NotOrganizationAdmin,
+
+    #[msg("An organization must keep at least one admin")]
+    LastOrganizationAdmin,
+
+    #[msg("Identity has been revoked by its parent")]
+    IdentityRevoked,
+
+    #[msg("Sub-identities are nested too deeply (max depth 4)")]
+    SubIdentityTooDeep,
+
+    #[msg("Identity is not the parent of this sub-identity")]
+    NotParentIdentity,
+
+    #[msg("AI agent identities must have an operator")]
+    OperatorRequired,
+
+    #[msg("Program identities must be owned by a program PDA and registered through CPI")]
+    ProgramIdentityRequiresCpi,
+
+    #[msg("Identity kind does not allow this action")]
+    InvalidIdentityKind,
+
+    #[msg("Endpoint URL must not be empty and its label must be 1-32 printable characters")]
+    InvalidEndpoint,
+
+    #[msg("Too many service endpoints (max 8)")]
+    TooManyEndpoints,
+
+    #[msg("Service endpoint not found")]
+    EndpointNotFound,
+
+    #[msg("Tags must be 1-32 lowercase letters, digits, underscores or hyphens")]
+    InvalidTag,
+
+    #[msg("Too many tags (max 8)")]
+    TooManyTags,
+
+    #[msg("The identity does not have this tag")]
+    TagNotFound,
+
+    #[msg("Remove the identity's tags first")]
+    IdentityHasTags,
+
+    #[msg("The moved tag entry and its membership must be passed")]
+    TagAccountsMissing,
+
+    #[msg("The tag index is empty")]
+    TagIndexEmpty,
+
+    #[msg("Price range must satisfy 0 < max_price and min_price <= max_price")]
+    InvalidPriceRange,
+
+    #[msg("This service is not currently offered")]
+    ServiceInactive,
+
+    #[msg("This service is paid in a different mint")]
+    ServiceMintMismatch,
+
+    #[msg("Payment amount is outside the service's price range")]
+    PriceOutOfRange,
+
+    #[msg("The agent does not accept payments in this mint")]
+    MintNotAccepted,
+
+    #[msg("Payment amount is below the agent's minimum for this mint")]
+    PaymentBelowMinimum,
+
+    #[msg("Payment amount is above the agent's maximum for this mint")]
+    PaymentAboveMaximum,
+
+    #[msg("Too many canonical mints (max 4)")]
+    TooManyCanonicalMints,
+
+    #[msg("The mint is not canonical")]
+    CanonicalMintNotFound,
+
+    #[msg("Payment amount must be greater than zero")]
+    ZeroPaymentAmount,
+
+    #[msg("Display name is already claimed by another identity")]
+    DisplayNameTaken,
+
+    #[msg("Close the identity's service listings first")]
+    IdentityHasServices,
+
+    #[msg("Remove the identity's payment policies first")]
+    IdentityHasPaymentPolicies,
+
+    #[msg("This username belonged to a closed identity and cannot be registered again")]
+    UsernameRetired,
+
+    #[msg("Have the identity removed from its organizations first")]
+    IdentityHasMemberships,
+
+    #[msg("Pass the display name claim if and only if the display name differs from the username")]
+    DisplayNameClaimMismatch,
+
+    #[msg("Revoke or close the identity's sub-identities first")]
+    IdentityHasSubIdentities,
+}
+
+#[derive(Accounts)]
+pub struct InitializeCollection<'info> {
+    // The program's upgrade authority, who becomes the registry admin
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        constraint = program.programdata_address()? == Some(program_data.key())
+    )]
+    pub program: Program<'info, crate::program::IdentityRegister>,
+
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(admin.key()) @ ErrorCode::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+
+    // The global registry config. Can only be created once.
+    #[account(
+        init,
+        payer = admin,
+        // Space = 8 (disc) + 32 (admin) + 32 (collection mint) + 8 (authority change delay)
+        //       + (4 + 4 * 32) (canonical mints) + 1 (bump)
+        space = 8 + 32 + 32 + 8 + 4 + MAX_CANONICAL_MINTS * 32 + 1,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, RegistryConfig>,
+
+    /// CHECK: PDA used only as a signer. It owns the collection NFT.
+    #[account(
+        seeds = [b"registry_authority"],
+        bump
+    )]
+    pub registry_authority: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        mint::decimals = 0,
+        mint::authority = registry_authority,
+        mint::freeze_authority = registry_authority,
+    )]
+    pub collection_mint: Box<Account<'info, Mint>>,
+
+    #[account(
+        init,
+        payer = admin,
+        associated_token::mint = collection_mint,
+        associated_token::authority = registry_authority,
+    )]
+    pub collection_token_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            collection_mint.key().as_ref()
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub collection_metadata: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            collection_mint.key().as_ref(),
+            b"edition"
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub collection_master_edition: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_metadata_program: Program<'info, Metadata>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+// This struct defines all the accounts required by our `register_identity` instruction
+#[derive(Accounts)]
+#[instruction(username: String)] // Make instruction args available
+pub struct RegisterIdentity<'info> {
+    
+    // Counts the identities registered by the user, created on their first registration
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = IdentityCounter::SPACE,
+        seeds = [b"identity_counter", authority.key().as_ref()],
+        bump
+    )]
+    pub identity_counter: Box<Account<'info, IdentityCounter>>,
+
+    // Lists the new identity among the ones the user owns
+    #[account(
+        init,
+        payer = payer,
+        space = OwnedIdentity::SPACE,
+        seeds = [b"owned_identity", authority.key().as_ref(), identity_counter.owned_count.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub owned_identity: Box<Account<'info, OwnedIdentity>>,
+
+    // This creates the new PDA account
+    #[account(
+        init,
+        payer = payer,
+        space = IdentityAccount::SPACE,
+        // Seeds make the PDA unique to the user and the index of the identity
+        seeds = [b"identity", authority.key().as_ref(), identity_counter.count.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // The global username claim, seeded by the hash of the normalized username.
+    // `init_if_needed` lets us return `UsernameTaken` instead of a generic
+    // "already in use" error when someone else owns the name.
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = UsernameClaim::SPACE,
+        seeds = [b"username", username_seed(&username).as_ref()],
+        bump
+    )]
+    pub username_claim: Box<Account<'info, UsernameClaim>>,
+
+    /// CHECK: Only checked to be empty. It is created by `close_identity`
+    /// and retires the username for good.
+    #[account(
+        seeds = [b"username_tombstone", username_claim.key().as_ref()],
+        bump,
+        constraint = username_tombstone.data_is_empty() @ ErrorCode::UsernameRetired
+    )]
+    pub username_tombstone: UncheckedAccount<'info>,
+
+    /// CHECK: Only checked to be empty. It is created when the wallet closes
+    /// an identity or hands one over, and blocks it from registering again.
+    #[account(
+        seeds = [b"tombstone", authority.key().as_ref()],
+        bump,
+        constraint = tombstone.data_is_empty() @ ErrorCode::IdentityClosed
+    )]
+    pub tombstone: UncheckedAccount<'info>,
+
+    // The user who is creating the identity. Only signs to prove ownership.
+    pub authority: Signer<'info>,
+
+    // Pays for the accounts and fees. Can be the authority itself, or a
+    // sponsor such as an onboarding service.
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    // The Solana System Program, required to create new accounts
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: PDA used only as a signer. It is the mint, freeze and update
+    /// authority and the token delegate (for freezing) of every identity NFT.
+    #[account(
+        seeds = [b"registry_authority"],
+        bump
+    )]
+    pub registry_authority: UncheckedAccount<'info>,
+
+    #[account(
+        init, // We are initializing this mint account
+        payer = payer,
+        mint::decimals = 0, // NFTs must have 0 decimals
+        mint::authority = registry_authority, // The registry PDA is the mint authority
+        mint::freeze_authority = registry_authority, // The registry PDA is the freeze authority
+    )]
+    pub mint: Box<Account<'info, Mint>>,
+
+    #[account(
+        init, // Create the user's token account
+        payer = payer,
+        associated_token::mint = mint,
+        associated_token::authority = authority,
+    )]
+    pub token_account: Box<Account<'info, TokenAccount>>, // The user's ATA
+
+    // Reverse lookup from the NFT mint to the identity
+    #[account(
+        init,
+        payer = payer,
+        // Space = 8 (disc) + 32 (identity) + 32 (authority) + 1 (bump)
+        space = 8 + 32 + 32 + 1,
+        seeds = [b"identity_mint", mint.key().as_ref()],
+        bump
+    )]
+    pub identity_mint_record: Box<Account<'info, IdentityMintRecord>>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            mint.key().as_ref()
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub metadata_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            mint.key().as_ref(),
+            b"edition"
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub master_edition_account: UncheckedAccount<'info>,
+
+    // --- Registry Collection ---
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = collection_mint
+    )]
+    pub config: Box<Account<'info, RegistryConfig>>,
+
+    pub collection_mint: Box<Account<'info, Mint>>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            collection_mint.key().as_ref()
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub collection_metadata: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            collection_mint.key().as_ref(),
+            b"edition"
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub collection_master_edition: UncheckedAccount<'info>,
+
+    // --- Required Programs ---
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_metadata_program: Program<'info, Metadata>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+impl<'info> RegisterIdentity<'info> {
+    // Creates the identity and mints its NFT. Shared by `register_identity`
+    // and `register_sub_identity`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn register(
+        &mut self,
+        bumps: &RegisterIdentityBumps,
+        username: String,
+        uri: String,
+        metadata_hash: [u8; 32],
+        kind: IdentityKind,
+        operator: Option<Operator>,
+    ) -> Result<()> {
+        let identity = &mut self.identity_account;
+        // Validate everything up front so clients get a clear program error
+        // instead of an opaque failure inside the Metaplex CPI.
+        let username = validate_username(&username)?;
+        validate_uri(&uri)?;
+
+        // Claim the username. The claim account is shared by every registration
+        // attempt for the same normalized name, so an existing owner means it's taken.
+        let username_claim = &mut self.username_claim;
+        if username_claim.identity != Pubkey::default() {
+            return err!(ErrorCode::UsernameTaken);
+        }
+
+        username_claim.authority = self.authority.key();
+        username_claim.identity = identity.key();
+        username_claim.username = username.clone();
+        username_claim.bump = bumps.username_claim;
+
+        // Take the next free index. Indices are never reused, so clients can
+        // enumerate the identities a wallet registered by deriving indices 0..count,
+        // and the ones it owns now by deriving owned slots 0..owned_count.
+        let identity_counter = &mut self.identity_counter;
+        identity_counter.authority = self.authority.key();
+        identity_counter.bump = bumps.identity_counter;
+        identity.index = identity_counter.count;
+        identity_counter.count = identity_counter.count.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+        identity.owned_index = identity_counter.next_owned_slot()?;
+
+        let owned_identity = &mut self.owned_identity;
+        owned_identity.authority = self.authority.key();
+        owned_identity.identity = identity.key();
+        owned_identity.bump = bumps.owned_identity;
+        
+        identity.authority = self.authority.key();
+        identity.creator = self.authority.key();
+        identity.mint = self.mint.key();
+        identity.username = username.clone();
+        identity.display_name = username.clone(); // Starts out as the username, see `update_identity`
+        identity.uri = uri.clone(); // This URI will point to the NFT's off-chain JSON metadata
+        identity.metadata_hash = metadata_hash;
+        identity.metadata_slot = Clock::get()?.slot; // Version 0 starts now
+        identity.bump = bumps.identity_account;
+
+        // Kind-specific rules. A program identity's authority must be one of the
+        // program's PDAs, which only the program itself can sign for through CPI.
+        // Checking the key rather than the call depth stops a wrapper program
+        // from forwarding a wallet's signature.
+        match kind {
+            IdentityKind::AiAgent if operator.is_none() => return err!(ErrorCode::OperatorRequired),
+            IdentityKind::Program if is_on_curve(&self.authority.key()) => {
+                return err!(ErrorCode::ProgramIdentityRequiresCpi)
+            }
+            _ => {}
+        }
+        identity.kind = kind;
+        if let Some(operator) = operator {
+            identity.set_operator(operator.key, operator.permissions, operator.expires_at)?;
+        }
+        
+        msg!("Identity account {} created for {} with username: {}", identity.index, identity.authority, identity.username);
+
+        // Reverse lookup from the NFT back to the identity
+        let identity_mint_record = &mut self.identity_mint_record;
+        identity_mint_record.identity = identity.key();
+        identity_mint_record.authority = identity.authority;
+        identity_mint_record.bump = bumps.identity_mint_record;
+
+        msg!("Minting Identity NFT...");
+
+        // The registry PDA is the mint, freeze and metadata update authority, so every
+        // identity NFT is issued by this program and can only change through it.
+        let registry_authority_seeds: &[&[&[u8]]] = &[&[b"registry_authority", &[bumps.registry_authority]]];
+
+        // CPI 1: Mint 1 token to the user's token account
+        mint_to(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                MintTo {
+                    mint: self.mint.to_account_info(),
+                    to: self.token_account.to_account_info(),
+                    authority: self.registry_authority.to_account_info(),
+                },
+                registry_authority_seeds,
+            ),
+            1, // Mint 1 token
+        )?;
+
+        msg!("Token minted");
+
+        // CPI 2: Create the Metaplex Metadata Account
+        let creators = vec![
+            Creator {
+                address: self.registry_authority.key(),
+                verified: true, // The registry PDA signs, so it is verified as the creator
+                share: 100,
+            }
+        ];
+
+        create_metadata_accounts_v3(
+            CpiContext::new_with_signer(
+                self.token_metadata_program.to_account_info(),
+                CreateMetadataAccountsV3 {
+                    metadata: self.metadata_account.to_account_info(),
+                    mint: self.mint.to_account_info(),
+                    mint_authority: self.registry_authority.to_account_info(),
+                    payer: self.payer.to_account_info(),
+                    update_authority: self.registry_authority.to_account_info(),
+                    system_program: self.system_program.to_account_info(),
+                    rent: self.rent.to_account_info(),
+                },
+                registry_authority_seeds,
+            ),
+            DataV2 {
+                name: username, // Use the username from the instruction
+                symbol: kind.symbol().to_string(), // The kind, so wallets and indexers see it on-chain
+                uri,            // Use the URI from the instruction
+                seller_fee_basis_points: 0,
+                creators: Some(creators),
+                // Unverified until CPI 3 below
+                collection: Some(Collection {
+                    verified: false,
+                    key: self.collection_mint.key(),
+                }),
+                uses: None,
+            },
+            true,  // is_mutable: Only the registry PDA can update it, via `update_identity`
+            true,  // update_authority_is_signer
+            None,  // collection_details
+        )?;
+
+        msg!("Metadata account created");
+
+        // CPI 3: Verify the NFT as a member of the registry collection
+        verify_sized_collection_item(
+            CpiContext::new_with_signer(
+                self.token_metadata_program.to_account_info(),
+                VerifySizedCollectionItem {
+                    payer: self.payer.to_account_info(),
+                    metadata: self.metadata_account.to_account_info(),
+                    collection_authority: self.registry_authority.to_account_info(),
+                    collection_mint: self.collection_mint.to_account_info(),
+                    collection_metadata: self.collection_metadata.to_account_info(),
+                    collection_master_edition: self.collection_master_edition.to_account_info(),
+                },
+                registry_authority_seeds,
+            ),
+            None, // collection_authority_record: the registry PDA is the collection's update authority
+        )?;
+
+        msg!("Collection verified");
+
+        // CPI 4: Create the Metaplex Master Edition Account (locks supply to 1).
+        // Metaplex moves the mint and freeze authorities to the edition account.
+        create_master_edition_v3(
+            CpiContext::new_with_signer(
+                self.token_metadata_program.to_account_info(),
+                CreateMasterEditionV3 {
+                    edition: self.master_edition_account.to_account_info(),
+                    mint: self.mint.to_account_info(),
+                    update_authority: self.registry_authority.to_account_info(),
+                    mint_authority: self.registry_authority.to_account_info(),
+                    payer: self.payer.to_account_info(),
+                    metadata: self.metadata_account.to_account_info(),
+                    token_program: self.token_program.to_account_info(),
+                    system_program: self.system_program.to_account_info(),
+                    rent: self.rent.to_account_info(),
+                },
+                registry_authority_seeds,
+            ),
+            Some(0), // Max supply 0 = locked. This is what makes it a 1-of-1 NFT.
+        )?;
+
+        msg!("Master Edition created");
+
+        // CPI 5: Make the NFT soulbound. The registry PDA is approved as the token
+        // delegate and freezes the token account through Metaplex (the master edition
+        // is the mint's freeze authority). A frozen account can't transfer its token,
+        // and the owner can't revoke the delegate while it is frozen.
+        // The registry PDA is also made the close authority, so an authority change
+        // can close the emptied account without the old key.
+        approve(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                Approve {
+                    to: self.token_account.to_account_info(),
+                    delegate: self.registry_authority.to_account_info(),
+                    authority: self.authority.to_account_info(),
+                },
+            ),
+            1,
+        )?;
+
+        set_authority(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                SetAuthority {
+                    current_authority: self.authority.to_account_info(),
+                    account_or_mint: self.token_account.to_account_info(),
+                },
+            ),
+            AuthorityType::CloseAccount,
+            Some(self.registry_authority.key()),
+        )?;
+
+        freeze_delegated_account(
+            CpiContext::new_with_signer(
+                self.token_metadata_program.to_account_info(),
+                FreezeDelegatedAccount {
+                    metadata: self.metadata_account.to_account_info(),
+                    delegate: self.registry_authority.to_account_info(),
+                    token_account: self.token_account.to_account_info(),
+                    edition: self.master_edition_account.to_account_info(),
+                    mint: self.mint.to_account_info(),
+                    token_program: self.token_program.to_account_info(),
+                },
+                registry_authority_seeds,
+            ),
+        )?;
+
+        msg!("Token account frozen. Identity NFT mint complete!");
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(username: String)]
+pub struct RegisterSubIdentity<'info> {
+    // The child identity's accounts, exactly as in `register_identity`
+    pub register: RegisterIdentity<'info>,
+
+    // The parent's owner, or an operator allowed to manage sub-identities
+    pub parent_signer: Signer<'info>,
+
+    // Revoked identities can't vouch for new ones
+    #[account(
+        mut,
+        seeds = [b"identity", parent_identity.creator.as_ref(), parent_identity.index.to_le_bytes().as_ref()],
+        bump = parent_identity.bump,
+        constraint = !parent_identity.revoked @ ErrorCode::IdentityRevoked
+    )]
+    pub parent_identity: Box<Account<'info, IdentityAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeSubIdentity<'info> {
+    // The parent's owner, or an operator allowed to manage sub-identities
+    pub parent_signer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", parent_identity.creator.as_ref(), parent_identity.index.to_le_bytes().as_ref()],
+        bump = parent_identity.bump
+    )]
+    pub parent_identity: Box<Account<'info, IdentityAccount>>,
+
+    // A child can only be revoked once, so it leaves the parent's count once
+    #[account(
+        mut,
+        seeds = [b"identity", child_identity.creator.as_ref(), child_identity.index.to_le_bytes().as_ref()],
+        bump = child_identity.bump,
+        constraint = child_identity.parent == parent_identity.key() @ ErrorCode::NotParentIdentity,
+        constraint = !child_identity.revoked @ ErrorCode::IdentityRevoked
+    )]
+    pub child_identity: Box<Account<'info, IdentityAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(uri: String, metadata_hash: [u8; 32], display_name: Option<String>)]
+pub struct UpdateIdentity<'info> {
+    // The identity owner, or an operator with `OPERATOR_UPDATE_METADATA`. Pays for the archive.
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    /// CHECK: The identity's owner. Gets the rent of a released display name claim.
+    #[account(mut)]
+    pub authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = mint,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // The claim on the new display name (the current one if it doesn't change).
+    // Created if it doesn't exist yet. `init_if_needed` lets us return
+    // `DisplayNameTaken` when another identity holds it.
+    #[account(
+        init_if_needed,
+        payer = signer,
+        space = UsernameClaim::SPACE,
+        seeds = [b"username", display_name_seed(display_name.as_deref().unwrap_or(&identity_account.display_name)).as_ref()],
+        bump
+    )]
+    pub display_name_claim: Box<Account<'info, UsernameClaim>>,
+
+    /// CHECK: Only checked to be empty. The username of a closed identity
+    /// can't come back as someone else's display name.
+    #[account(
+        seeds = [b"username_tombstone", display_name_claim.key().as_ref()],
+        bump,
+        constraint = display_name_tombstone.data_is_empty() @ ErrorCode::UsernameRetired
+    )]
+    pub display_name_tombstone: UncheckedAccount<'info>,
+
+    // The claim on the display name being replaced. Pass it only when it is
+    // released, i.e. when the old display name is neither a restyling of the
+    // username nor of the new display name. Its rent goes to the owner.
+    #[account(
+        mut,
+        seeds = [b"username", display_name_seed(&identity_account.display_name).as_ref()],
+        bump = previous_display_name_claim.bump,
+        constraint = previous_display_name_claim.identity == identity_account.key() @ ErrorCode::DisplayNameTaken
+    )]
+    pub previous_display_name_claim: Option<Box<Account<'info, UsernameClaim>>>,
+
+    // Archive of the metadata being replaced, at the identity's current version
+    #[account(
+        init,
+        payer = signer,
+        space = MetadataVersion::SPACE,
+        seeds = [b"metadata_version", identity_account.key().as_ref(), identity_account.metadata_version.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub metadata_version: Box<Account<'info, MetadataVersion>>,
+
+    // The identity NFT mint
+    pub mint: Box<Account<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            mint.key().as_ref()
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub metadata_account: Box<Account<'info, MetadataAccount>>,
+
+    /// CHECK: PDA used only as a signer for the metadata update
+    #[account(
+        seeds = [b"registry_authority"],
+        bump
+    )]
+    pub registry_authority: UncheckedAccount<'info>,
+
+    pub token_metadata_program: Program<'info, Metadata>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeEndpoints<'info> {
+    // The identity owner, or an operator with `OPERATOR_MANAGE_ENDPOINTS`. Pays for the account.
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        init,
+        payer = signer,
+        space = ServiceEndpoints::space(0),
+        seeds = [b"endpoints", identity_account.key().as_ref()],
+        bump
+    )]
+    pub endpoints: Box<Account<'info, ServiceEndpoints>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AddEndpoint<'info> {
+    // The identity owner, or an operator with `OPERATOR_MANAGE_ENDPOINTS`. Pays for the extra space.
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"endpoints", identity_account.key().as_ref()],
+        bump = endpoints.bump,
+        realloc = ServiceEndpoints::space(endpoints.endpoints.len() + 1),
+        realloc::payer = signer,
+        realloc::zero = false
+    )]
+    pub endpoints: Box<Account<'info, ServiceEndpoints>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateEndpoint<'info> {
+    // The identity owner, or an operator with `OPERATOR_MANAGE_ENDPOINTS`
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"endpoints", identity_account.key().as_ref()],
+        bump = endpoints.bump
+    )]
+    pub endpoints: Box<Account<'info, ServiceEndpoints>>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveEndpoint<'info> {
+    // The identity owner, or an operator with `OPERATOR_MANAGE_ENDPOINTS`
+    pub signer: Signer<'info>,
+
+    /// CHECK: The identity's owner, who receives the freed rent even when an
+    /// operator signs
+    #[account(mut)]
+    pub authority: UncheckedAccount<'info>,
+
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"endpoints", identity_account.key().as_ref()],
+        bump = endpoints.bump,
+        realloc = ServiceEndpoints::space(endpoints.endpoints.len().saturating_sub(1)),
+        realloc::payer = authority,
+        realloc::zero = false
+    )]
+    pub endpoints: Box<Account<'info, ServiceEndpoints>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(tag: String)]
+pub struct AddTag<'info> {
+    // The identity owner, or an operator with `OPERATOR_UPDATE_METADATA`. Pays for the index accounts.
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // Seeded by the hash of the normalized tag, like username claims
+    #[account(
+        init_if_needed,
+        payer = signer,
+        space = TagIndex::SPACE,
+        seeds = [b"tag", username_seed(&tag).as_ref()],
+        bump
+    )]
+    pub tag_index: Box<Account<'info, TagIndex>>,
+
+    // The next free slot of the index
+    #[account(
+        init,
+        payer = signer,
+        space = TagEntry::SPACE,
+        seeds = [b"tag_entry", tag_index.key().as_ref(), tag_index.count.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub tag_entry: Box<Account<'info, TagEntry>>,
+
+    // Reverse lookup of the identity's slot. Its existence also stops duplicate tags.
+    #[account(
+        init,
+        payer = signer,
+        space = TagMember::SPACE,
+        seeds = [b"tag_member", tag_index.key().as_ref(), identity_account.key().as_ref()],
+        bump
+    )]
+    pub tag_member: Box<Account<'info, TagMember>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(tag: String)]
+pub struct RemoveTag<'info> {
+    // The identity owner, or an operator with `OPERATOR_UPDATE_METADATA`. Receives the freed rent.
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"tag", username_seed(&tag).as_ref()],
+        bump = tag_index.bump
+    )]
+    pub tag_index: Box<Account<'info, TagIndex>>,
+
+    // The identity's slot in the index
+    #[account(
+        mut,
+        seeds = [b"tag_member", tag_index.key().as_ref(), identity_account.key().as_ref()],
+        bump = tag_member.bump,
+        close = signer
+    )]
+    pub tag_member: Box<Account<'info, TagMember>>,
+
+    // The last slot of the index, which is always closed. Its address depends
+    // on `count - 1`, so it is checked in the handler once that can't underflow.
+    #[account(
+        mut,
+        has_one = tag_index,
+        close = signer
+    )]
+    pub last_entry: Box<Account<'info, TagEntry>>,
+
+    // The removed slot, which takes over the last entry. Omit it when removing the last entry.
+    #[account(
+        mut,
+        seeds = [b"tag_entry", tag_index.key().as_ref(), tag_member.index.to_le_bytes().as_ref()],
+        bump = entry.bump
+    )]
+    pub entry: Option<Box<Account<'info, TagEntry>>>,
+
+    // The membership of the moved identity. Omit it when removing the last entry.
+    #[account(
+        mut,
+        seeds = [b"tag_member", tag_index.key().as_ref(), last_entry.identity.as_ref()],
+        bump = last_member.bump
+    )]
+    pub last_member: Option<Box<Account<'info, TagMember>>>,
+}
+
+#[derive(Accounts)]
+#[instruction(service_id: u32)]
+pub struct CreateService<'info> {
+    // The identity owner, or an operator with `OPERATOR_MANAGE_SERVICES`. Pays for the listing.
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        init,
+        payer = signer,
+        space = ServiceOffering::SPACE,
+        seeds = [b"service", identity_account.key().as_ref(), service_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub service: Box<Account<'info, ServiceOffering>>,
+
+    // The only mint the service accepts
+    pub mint: Account<'info, Mint>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ManageService<'info> {
+    // The identity owner, or an operator with `OPERATOR_MANAGE_SERVICES`
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"service", identity_account.key().as_ref(), service.service_id.to_le_bytes().as_ref()],
+        bump = service.bump
+    )]
+    pub service: Box<Account<'info, ServiceOffering>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseService<'info> {
+    // The identity owner, or an operator with `OPERATOR_MANAGE_SERVICES`
+    pub signer: Signer<'info>,
+
+    /// CHECK: The identity's owner, who receives the rent even when an
+    /// operator signs
+    #[account(mut)]
+    pub authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"service", identity_account.key().as_ref(), service.service_id.to_le_bytes().as_ref()],
+        bump = service.bump,
+        close = authority
+    )]
+    pub service: Box<Account<'info, ServiceOffering>>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaymentPolicy<'info> {
+    // The identity owner, or an operator with `OPERATOR_MANAGE_SERVICES`
+    pub signer: Signer<'info>,
+
+    // Pays for the policy account. May be the signer.
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // The accepted mint
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = PaymentPolicy::SPACE,
+        seeds = [b"payment_policy", identity_account.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub payment_policy: Box<Account<'info, PaymentPolicy>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemovePaymentPolicy<'info> {
+    // The identity owner, or an operator with `OPERATOR_MANAGE_SERVICES`
+    pub signer: Signer<'info>,
+
+    /// CHECK: The identity's owner, who receives the rent even when an
+    /// operator signs
+    #[account(mut)]
+    pub authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"payment_policy", identity_account.key().as_ref(), payment_policy.mint.as_ref()],
+        bump = payment_policy.bump,
+        close = authority
+    )]
+    pub payment_policy: Box<Account<'info, PaymentPolicy>>,
+}
+
+#[derive(Accounts)]
+pub struct ManageOperators<'info> {
+    // The identity owner
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseIdentity<'info> {
+    // The agent (authority) closing its identity. Receives all reclaimed rent.
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority,
+        has_one = mint,
+        constraint = identity_account.tags.is_empty() @ ErrorCode::IdentityHasTags,
+        constraint = identity_account.service_count == 0 @ ErrorCode::IdentityHasServices,
+        constraint = identity_account.payment_policy_count == 0 @ ErrorCode::IdentityHasPaymentPolicies,
+        constraint = identity_account.org_membership_count == 0 @ ErrorCode::IdentityHasMemberships,
+        constraint = identity_account.sub_identity_count == 0 @ ErrorCode::IdentityHasSubIdentities,
+        close = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // The parent, if the identity is a sub-identity that hasn't been revoked,
+    // so it can be taken out of the parent's count
+    #[account(
+        mut,
+        seeds = [b"identity", parent_identity.creator.as_ref(), parent_identity.index.to_le_bytes().as_ref()],
+        bump = parent_identity.bump,
+        constraint = parent_identity.key() == identity_account.parent @ ErrorCode::NotParentIdentity
+    )]
+    pub parent_identity: Option<Box<Account<'info, IdentityAccount>>>,
+
+    #[account(
+        mut,
+        seeds = [b"identity_mint", mint.key().as_ref()],
+        bump = identity_mint_record.bump,
+        close = authority
+    )]
+    pub identity_mint_record: Box<Account<'info, IdentityMintRecord>>,
+
+    // The identity's entry in the authority's list, which leaves a gap
+    #[account(
+        mut,
+        seeds = [b"owned_identity", authority.key().as_ref(), identity_account.owned_index.to_le_bytes().as_ref()],
+        bump = owned_identity.bump,
+        close = authority
+    )]
+    pub owned_identity: Box<Account<'info, OwnedIdentity>>,
+
+    // The claim's rent is returned. The username tombstone keeps it from
+    // being claimed again.
+    #[account(
+        mut,
+        seeds = [b"username", username_seed(&identity_account.username).as_ref()],
+        bump = username_claim.bump,
+        has_one = authority,
+        close = authority
+    )]
+    pub username_claim: Box<Account<'info, UsernameClaim>>,
+
+    // The claim on the display name, if it isn't a restyling of the username
+    #[account(
+        mut,
+        seeds = [b"username", display_name_seed(&identity_account.display_name).as_ref()],
+        bump = display_name_claim.bump,
+        constraint = display_name_claim.identity == identity_account.key() @ ErrorCode::DisplayNameTaken,
+        close = authority
+    )]
+    pub display_name_claim: Option<Box<Account<'info, UsernameClaim>>>,
+
+    // Closing the reputation account is optional. Leave it out to keep
+    // the reputation history on-chain.
+    #[account(
+        mut,
+        seeds = [b"reputation", identity_account.key().as_ref()],
+        bump = reputation_account.bump,
+        has_one = authority,
+        close = authority
+    )]
+    pub reputation_account: Option<Box<Account<'info, ReputationAccount>>>,
+
+    /// CHECK: The identity's service endpoints PDA. It may not exist, so it
+    /// is closed in the handler if it does.
+    #[account(
+        mut,
+        seeds = [b"endpoints", identity_account.key().as_ref()],
+        bump
+    )]
+    pub endpoints: UncheckedAccount<'info>,
+
+    /// CHECK: Only checked to be empty. A pending authority change must be
+    /// cancelled first, or its rent would be stranded on a closed identity.
+    #[account(
+        seeds = [b"authority_change", identity_account.key().as_ref()],
+        bump,
+        constraint = pending_authority_change.data_is_empty() @ ErrorCode::AuthorityChangePending
+    )]
+    pub pending_authority_change: UncheckedAccount<'info>,
+
+    // Retires the username, so the reputation it built up can't be wiped by
+    // registering it again with a fresh identity
+    #[account(
+        init,
+        payer = authority,
+        space = Tombstone::SPACE,
+        seeds = [b"username_tombstone", username_claim.key().as_ref()],
+        bump
+    )]
+    pub username_tombstone: Box<Account<'info, Tombstone>>,
+
+    // Blocks the wallet from registering again, so it can't start over
+    // under a new username either
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = Tombstone::SPACE,
+        seeds = [b"tombstone", authority.key().as_ref()],
+        bump
+    )]
+    pub tombstone: Box<Account<'info, Tombstone>>,
+
+    // The identity NFT mint
+    #[account(mut)]
+    pub mint: Box<Account<'info, Mint>>,
+
+    // The authority's token account, which must hold the identity NFT
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = authority,
+        constraint = token_account.amount == 1 @ ErrorCode::NotIdentityHolder
+    )]
+    pub token_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            mint.key().as_ref()
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub metadata_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            mint.key().as_ref(),
+            b"edition"
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub master_edition_account: UncheckedAccount<'info>,
+
+    /// CHECK: PDA used only as a signer to thaw the NFT
+    #[account(
+        seeds = [b"registry_authority"],
+        bump
+    )]
+    pub registry_authority: UncheckedAccount<'info>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Box<Account<'info, RegistryConfig>>,
+
+    /// CHECK: The registry collection's metadata, so the collection size can be decremented
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            config.collection_mint.as_ref()
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub collection_metadata: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub token_metadata_program: Program<'info, Metadata>,
+    pub system_program: Program<'info, System>,
+}
+
+// Reusable accounts that prove `holder` holds the NFT of `identity_account`.
+// Embed it in another instruction's accounts to gate it on NFT ownership
+// rather than on the authority key alone.
+#[derive(Accounts)]
+pub struct IdentityHolder<'info> {
+    pub holder: Signer<'info>,
+
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        token::mint = identity_account.mint,
+        token::authority = holder,
+        constraint = identity_token_account.amount == 1 @ ErrorCode::NotIdentityHolder
+    )]
+    pub identity_token_account: Box<Account<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyIdentity<'info> {
+    pub identity_holder: IdentityHolder<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAuthorityChangeDelay<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub config: Account<'info, RegistryConfig>,
+}
+
+#[derive(Accounts)]
+pub struct ManageCanonicalMints<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub config: Account<'info, RegistryConfig>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeAuthorityChange<'info> {
+    // The current authority
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // Provides the timelock
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Box<Account<'info, RegistryConfig>>,
+
+    // Only one change can be pending per identity
+    #[account(
+        init,
+        payer = authority,
+        // Space = 8 (disc) + 32 (identity) + 32 (current) + 32 (new) + 8 (unlock_at) + 1 (bump)
+        space = 8 + 32 + 32 + 32 + 8 + 1,
+        seeds = [b"authority_change", identity_account.key().as_ref()],
+        bump
+    )]
+    pub pending_authority_change: Account<'info, PendingAuthorityChange>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelAuthorityChange<'info> {
+    // The current authority
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"authority_change", identity_account.key().as_ref()],
+        bump = pending_authority_change.bump,
+        close = authority
+    )]
+    pub pending_authority_change: Account<'info, PendingAuthorityChange>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthorityChange<'info> {
+    // The new authority. Pays for its token account if it doesn't exist yet.
+    #[account(mut)]
+    pub new_authority: Signer<'info>,
+
+    /// CHECK: The current authority. Checked against the pending change and
+    /// receives its rent.
+    #[account(mut)]
+    pub old_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"authority_change", identity_account.key().as_ref()],
+        bump = pending_authority_change.bump,
+        constraint = pending_authority_change.current_authority == old_authority.key(),
+        constraint = pending_authority_change.new_authority == new_authority.key(),
+        close = old_authority
+    )]
+    pub pending_authority_change: Box<Account<'info, PendingAuthorityChange>>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        constraint = identity_account.authority == old_authority.key(),
+        has_one = mint
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    /// CHECK: The identity's reputation PDA. It may not exist, so it is
+    /// deserialized and updated in the handler.
+    #[account(
+        mut,
+        seeds = [b"reputation", identity_account.key().as_ref()],
+        bump
+    )]
+    pub reputation_account: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"username", username_seed(&identity_account.username).as_ref()],
+        bump = username_claim.bump
+    )]
+    pub username_claim: Box<Account<'info, UsernameClaim>>,
+
+    // The claim on the display name, if it isn't a restyling of the username
+    #[account(
+        mut,
+        seeds = [b"username", display_name_seed(&identity_account.display_name).as_ref()],
+        bump = display_name_claim.bump,
+        constraint = display_name_claim.identity == identity_account.key() @ ErrorCode::DisplayNameTaken
+    )]
+    pub display_name_claim: Option<Box<Account<'info, UsernameClaim>>>,
+
+    #[account(
+        mut,
+        seeds = [b"identity_mint", mint.key().as_ref()],
+        bump = identity_mint_record.bump
+    )]
+    pub identity_mint_record: Box<Account<'info, IdentityMintRecord>>,
+
+    // The identity's entry in the old authority's list, which leaves a gap
+    #[account(
+        mut,
+        seeds = [b"owned_identity", old_authority.key().as_ref(), identity_account.owned_index.to_le_bytes().as_ref()],
+        bump = old_owned_identity.bump,
+        close = old_authority
+    )]
+    pub old_owned_identity: Box<Account<'info, OwnedIdentity>>,
+
+    // The new authority's counter, created if it never registered an identity
+    #[account(
+        init_if_needed,
+        payer = new_authority,
+        space = IdentityCounter::SPACE,
+        seeds = [b"identity_counter", new_authority.key().as_ref()],
+        bump
+    )]
+    pub new_identity_counter: Box<Account<'info, IdentityCounter>>,
+
+    // The identity's entry in the new authority's list
+    #[account(
+        init,
+        payer = new_authority,
+        space = OwnedIdentity::SPACE,
+        seeds = [b"owned_identity", new_authority.key().as_ref(), new_identity_counter.owned_count.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub new_owned_identity: Box<Account<'info, OwnedIdentity>>,
+
+    // Blocks the old key from registering again. It may already exist if the
+    // key closed or handed over another identity before.
+    #[account(
+        init_if_needed,
+        payer = new_authority,
+        space = Tombstone::SPACE,
+        seeds = [b"tombstone", old_authority.key().as_ref()],
+        bump
+    )]
+    pub old_tombstone: Box<Account<'info, Tombstone>>,
+
+    /// CHECK: Only checked to be empty, so a closed key can't receive an identity
+    #[account(
+        seeds = [b"tombstone", new_authority.key().as_ref()],
+        bump,
+        constraint = new_tombstone.data_is_empty() @ ErrorCode::IdentityClosed
+    )]
+    pub new_tombstone: UncheckedAccount<'info>,
+
+    // The identity NFT mint
+    pub mint: Box<Account<'info, Mint>>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = old_authority,
+    )]
+    pub old_token_account: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed,
+        payer = new_authority,
+        associated_token::mint = mint,
+        associated_token::authority = new_authority,
+    )]
+    pub new_token_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            mint.key().as_ref()
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub metadata_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because we are passing the right seeds
+    #[account(
+        seeds = [
+            b"metadata",
+            token_metadata_program.key().as_ref(),
+            mint.key().as_ref(),
+            b"edition"
+        ],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub master_edition_account: UncheckedAccount<'info>,
+
+    /// CHECK: PDA used only as a signer to move the NFT
+    #[account(
+        seeds = [b"registry_authority"],
+        bump
+    )]
+    pub registry_authority: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_metadata_program: Program<'info, Metadata>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeReputation<'info> {
+    // The agent (authority) who is creating their reputation account
+    pub authority: Signer<'info>,
+
+    // Pays for the reputation account. Can be the authority itself.
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The identity account, used as a check to ensure the signer
+    // is a registered agent.
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        // We constrain that the authority on the identity account
+        // matches the signer.
+        has_one = authority
+    )]
+    pub identity_account: Account<'info, IdentityAccount>,
+
+    // The new reputation account PDA
+    #[account(
+        init,
+        payer = payer,
+        space = ReputationAccount::SPACE,
+        seeds = [b"reputation", identity_account.key().as_ref()],
+        bump
+    )]
+    pub reputation_account: Account<'info, ReputationAccount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct LogServiceTransaction<'info> {
+    // The user paying for the service
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: This is the agent (identity) being paid.
+    /// We validate it by checking its relationship to the reputation_account.
+    pub authority: AccountInfo<'info>,
+
+    // The agent's identity. Suspended and revoked agents can't take payments.
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority,
+        constraint = !identity_account.suspended @ ErrorCode::IdentitySuspended,
+        constraint = !identity_account.revoked @ ErrorCode::IdentityRevoked
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // The agent's reputation account (to be updated)
+    #[account(
+        mut,
+        seeds = [b"reputation", identity_account.key().as_ref()],
+        bump = reputation_account.bump,
+        // Crucial check: ensures this reputation account
+        // belongs to the agent_authority we are paying.
+        has_one = authority
+    )]
+    pub reputation_account: Account<'info, ReputationAccount>,
+
+    // The mint of the token being transferred (e.g., USDC)
+    pub mint: Account<'info, Mint>,
+
+    // The payer's token account (from which to send)
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = payer,
+    )]
+    pub payer_token_account: Account<'info, TokenAccount>,
+    
+    // The agent's token account (to receive payment)
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = authority,
+    )]
+    pub agent_token_account: Account<'info, TokenAccount>,
+
+    // Per payer/agent record of paid transactions, created on the first payment
+    #[account(
+        init_if_needed,
+        payer = payer,
+        // Space = 8 (disc) + 32 (agent) + 32 (payer) + 8 (txns) + 8 (amount) + 4 (metadata version) + 1 (bump)
+        space = 8 + 32 + 32 + 8 + 8 + 4 + 1,
+        seeds = [b"payment", identity_account.key().as_ref(), payer.key().as_ref()],
+        bump
+    )]
+    pub payment_record: Account<'info, PaymentRecord>,
+
+    // The agent's transactions and volume in `mint`, created on the first payment in it
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = MintVolume::SPACE,
+        seeds = [b"mint_volume", identity_account.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub mint_volume: Box<Account<'info, MintVolume>>,
+
+    // Lists the canonical mints
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Box<Account<'info, RegistryConfig>>,
+
+    // The agent's rules for `mint`. Not needed when paying for a listed service.
+    // Otherwise the payment is rejected without it.
+    #[account(
+        seeds = [b"payment_policy", identity_account.key().as_ref(), mint.key().as_ref()],
+        bump = payment_policy.bump
+    )]
+    pub payment_policy: Option<Box<Account<'info, PaymentPolicy>>>,
+
+    // The agent's listing for the service being paid for, if any
+    #[account(
+        mut,
+        seeds = [b"service", identity_account.key().as_ref(), service.service_id.to_le_bytes().as_ref()],
+        bump = service.bump,
+        has_one = mint @ ErrorCode::ServiceMintMismatch,
+        constraint = service.active @ ErrorCode::ServiceInactive
+    )]
+    pub service: Option<Box<Account<'info, ServiceOffering>>>,
+
+    // Required programs
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SubmitReview<'info> {
+    // The user leaving the review (and paying for the review account)
+    #[account(
+        mut,
+        constraint = reviewer.key() != authority.key() @ ErrorCode::SelfReview
+    )]
+    pub reviewer: Signer<'info>,
+
+    /// CHECK: This is the agent (identity) being reviewed.
+    /// We validate it by checking its relationship to the identity_account.
+    pub authority: AccountInfo<'info>,
+
+    // The identity being reviewed
+    #[account(
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // The agent's reputation account (to be updated)
+    #[account(
+        mut,
+        seeds = [b"reputation", identity_account.key().as_ref()],
+        bump = reputation_account.bump,
+        has_one = authority
+    )]
+    pub reputation_account: Account<'info, ReputationAccount>,
+
+    /// CHECK: Proof that the reviewer has paid this agent through `log_service_transaction`.
+    /// It may not exist, so it is deserialized and checked in the handler.
+    #[account(
+        seeds = [b"payment", identity_account.key().as_ref(), reviewer.key().as_ref()],
+        bump
+    )]
+    pub payment_record: UncheckedAccount<'info>,
+
+    // The new review PDA. Seeding by identity and reviewer limits
+    // each reviewer to a single review per agent.
+    #[account(
+        init,
+        payer = reviewer,
+        // Space = 8 (disc) + 32 (agent) + 32 (reviewer) + 1 (rating) + (4 + 200) (uri) + 1 (bump)
+        space = 8 + 32 + 32 + 1 + 4 + 200 + 1,
+        seeds = [b"review", identity_account.key().as_ref(), reviewer.key().as_ref()],
+        bump
+    )]
+    pub review_account: Account<'info, ReviewAccount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CreateOrganization<'info> {
+    // The creator, who becomes the first admin and pays for the accounts
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    // The creator's identity. Only organization identities can create organizations.
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority,
+        constraint = identity_account.kind == IdentityKind::Organization @ ErrorCode::InvalidIdentityKind
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // Seeded by the hash of the normalized name, so organization names are unique
+    #[account(
+        init,
+        payer = authority,
+        space = OrganizationAccount::SPACE,
+        seeds = [b"organization", username_seed(&name).as_ref()],
+        bump
+    )]
+    pub organization: Box<Account<'info, OrganizationAccount>>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = OrganizationMember::SPACE,
+        seeds = [b"org_member", organization.key().as_ref(), identity_account.key().as_ref()],
+        bump
+    )]
+    pub membership: Box<Account<'info, OrganizationMember>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct JoinOrganization<'info> {
+    // The joining identity's authority. Pays for the membership.
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    // An admin of the organization, approving the new member
+    pub admin: Signer<'info>,
+
+    // The admin's identity. Checked by its current authority, so an admin
+    // keeps its role through an authority change.
+    #[account(
+        seeds = [b"identity", admin_identity.creator.as_ref(), admin_identity.index.to_le_bytes().as_ref()],
+        bump = admin_identity.bump,
+        constraint = admin_identity.authority == admin.key() @ ErrorCode::NotOrganizationAdmin
+    )]
+    pub admin_identity: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        seeds = [b"org_member", organization.key().as_ref(), admin_identity.key().as_ref()],
+        bump = admin_membership.bump,
+        constraint = admin_membership.role == OrganizationRole::Admin @ ErrorCode::NotOrganizationAdmin
+    )]
+    pub admin_membership: Box<Account<'info, OrganizationMember>>,
+
+    #[account(
+        mut,
+        seeds = [b"organization", username_seed(&organization.name).as_ref()],
+        bump = organization.bump
+    )]
+    pub organization: Box<Account<'info, OrganizationAccount>>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = OrganizationMember::SPACE,
+        seeds = [b"org_member", organization.key().as_ref(), identity_account.key().as_ref()],
+        bump
+    )]
+    pub membership: Box<Account<'info, OrganizationMember>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveMember<'info> {
+    // An admin of the organization
+    pub admin: Signer<'info>,
+
+    // The admin's identity. Checked by its current authority, so an admin
+    // keeps its role through an authority change.
+    #[account(
+        seeds = [b"identity", admin_identity.creator.as_ref(), admin_identity.index.to_le_bytes().as_ref()],
+        bump = admin_identity.bump,
+        constraint = admin_identity.authority == admin.key() @ ErrorCode::NotOrganizationAdmin
+    )]
+    pub admin_identity: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        seeds = [b"org_member", organization.key().as_ref(), admin_identity.key().as_ref()],
+        bump = admin_membership.bump,
+        constraint = admin_membership.role == OrganizationRole::Admin @ ErrorCode::NotOrganizationAdmin
+    )]
+    pub admin_membership: Box<Account<'info, OrganizationMember>>,
+
+    #[account(
+        mut,
+        seeds = [b"organization", username_seed(&organization.name).as_ref()],
+        bump = organization.bump
+    )]
+    pub organization: Box<Account<'info, OrganizationAccount>>,
+
+    // The membership being removed. Its rent goes back to the member.
+    #[account(
+        mut,
+        seeds = [b"org_member", organization.key().as_ref(), member_identity.key().as_ref()],
+        bump = membership.bump,
+        close = member_authority
+    )]
+    pub membership: Box<Account<'info, OrganizationMember>>,
+
+    // The removed member's identity, to keep its membership count right
+    #[account(
+        mut,
+        seeds = [b"identity", member_identity.creator.as_ref(), member_identity.index.to_le_bytes().as_ref()],
+        bump = member_identity.bump
+    )]
+    pub member_identity: Box<Account<'info, IdentityAccount>>,
+
+    /// CHECK: The member identity's current authority, which receives the rent.
+    /// The authority that joined may have handed the identity over since.
+    #[account(
+        mut,
+        constraint = member_authority.key() == member_identity.authority
+    )]
+    pub member_authority: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct LeaveOrganization<'info> {
+    // The member identity's current authority. Gets the membership's rent back.
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"identity", identity_account.creator.as_ref(), identity_account.index.to_le_bytes().as_ref()],
+        bump = identity_account.bump,
+        has_one = authority
+    )]
+    pub identity_account: Box<Account<'info, IdentityAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"organization", username_seed(&organization.name).as_ref()],
+        bump = organization.bump
+    )]
+    pub organization: Box<Account<'info, OrganizationAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"org_member", organization.key().as_ref(), identity_account.key().as_ref()],
+        bump = membership.bump,
+        close = authority
+    )]
+    pub membership: Box<Account<'info, OrganizationMember>>,
+}
+
+#[derive(Accounts)]
+pub struct SyncMemberReputation<'info> {
+    #[account(
+        mut,
+        seeds = [b"organization", username_seed(&organization.name).as_ref()],
+        bump = organization.bump
+    )]
+    pub organization: Box<Account<'info, OrganizationAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"org_member", organization.key().as_ref(), membership.identity.as_ref()],
+        bump = membership.bump
+    )]
+    pub membership: Box<Account<'info, OrganizationMember>>,
+
+    // The member's reputation account
+    #[account(
+        seeds = [b"reputation", membership.identity.as_ref()],
+        bump = reputation_account.bump
+    )]
+    pub reputation_account: Box<Account<'info, ReputationAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyMembership<'info> {
+    pub identity_holder: IdentityHolder<'info>,
+
+    #[account(
+        seeds = [b"organization", username_seed(&organization.name).as_ref()],
+        bump = organization.bump
+    )]
+    pub organization: Box<Account<'info, OrganizationAccount>>,
+
+    #[account(
+        seeds = [b"org_member", organization.key().as_ref(), identity_holder.identity_account.key().as_ref()],
+        bump = membership.bump
+    )]
+    pub membership: Box<Account<'info, OrganizationMember>>,
+}
 
 // This struct defines the data to be stored in the `IdentityAccount`
 #[account]
 pub struct IdentityAccount {
+    pub authority: Pubkey,          // The owner (human or multisig)
+    pub mint: Pubkey,               // The identity NFT mint
+    pub index: u32,                 // Position among the creator's identities
+    pub suspended: bool,            // Kill switch set by the owner
+    pub parent: Pubkey,             // The vouching identity, or the default key for a root identity
+    pub depth: u8,                  // Number of parents above this identity
+    pub revoked: bool,              // Set by the parent to withdraw its vouch
+    pub kind: IdentityKind,         // Who or what is behind the identity
+    pub creator: Pubkey,            // The wallet that registered the identity. Seeds the PDA, never changes.
+    pub username: String,           // e.g., "alice"
+    pub display_name: String,       // e.g., "Alice" (also the NFT name)
+    pub uri: String,                // e.g., "https://arweave.net/..."
+    pub metadata_hash: [u8; 32],    // SHA-256 of the document at `uri`, or zeroes if none was committed
+    pub metadata_version: u32,      // Number of earlier versions archived in `MetadataVersion` PDAs
+    pub metadata_slot: u64,         // Slot at which `uri` and `metadata_hash` were set
+    pub operators: Vec<Operator>,   // Scoped keys allowed to act for the agent
+    pub tags: Vec<String>,          // Capability tags, e.g. "translation"
+    pub service_count: u32,         // Open `ServiceOffering` listings
+    pub payment_policy_count: u32,  // Open `PaymentPolicy` accounts
+    pub org_membership_count: u32,  // Open `OrganizationMember` accounts
+    pub sub_identity_count: u32,    // Sub-identities vouched for and not revoked
+    pub owned_index: u32,           // Slot of the `OwnedIdentity` entry under the current authority
+    pub bump: u8,
+}
+
+impl IdentityAccount {
+    // Space = 8 (discriminator) + 32 (authority) + 32 (mint) + 4 (index) + 1 (suspended) + 32 (parent)
+    //       + 1 (depth) + 1 (revoked) + 1 (kind) + 32 (creator) + (4 + 32) (username) + (4 + 32) (display name)
+    //       + (4 + 200) (uri) + 32 (metadata hash) + 4 (metadata version) + 8 (metadata slot)
+    //       + (4 + 4 * 41) (operators) + (4 + 8 * (4 + 32)) (tags) + 4 (service count)
+    //       + 4 (payment policy count) + 4 (org membership count) + 4 (sub-identity count)
+    //       + 4 (owned index) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 32 + 4 + 1 + 32 + 1 + 1 + 1 + 32 + 4 + 32 + 4 + 32 + 4 + 200 + 32 + 4 + 8
+        + 4 + MAX_OPERATORS * Operator::SPACE + 4 + MAX_TAGS * (4 + MAX_TAG_LENGTH) + 4 + 4 + 4 + 4 + 4 + 1;
+
+    // Byte offset of `kind`, for `memcmp` filters. Everything before it is fixed size.
+    pub const KIND_OFFSET: usize = 8 + 32 + 32 + 4 + 1 + 32 + 1 + 1;
+
+    // Adds an operator key, or updates its permissions and expiry if it is already an operator
+    pub fn set_operator(&mut self, operator: Pubkey, permissions: u8, expires_at: i64) -> Result<()> {
+        if permissions == 0 || permissions & !OPERATOR_ALL_PERMISSIONS != 0 {
+            return err!(ErrorCode::InvalidOperatorPermissions);
+        }
+
+        if expires_at <= Clock::get()?.unix_timestamp {
+            return err!(ErrorCode::InvalidOperatorExpiry);
+        }
+
+        if operator == self.authority {
+            return err!(ErrorCode::InvalidOperatorPermissions);
+        }
+
+        match self.operators.iter_mut().find(|op| op.key == operator) {
+            Some(existing) => {
+                existing.permissions = permissions;
+                existing.expires_at = expires_at;
+            }
+            None => {
+                if self.operators.len() >= MAX_OPERATORS {
+                    return err!(ErrorCode::TooManyOperators);
+                }
+                self.operators.push(Operator { key: operator, permissions, expires_at });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Checks a metadata document fetched from `uri` against the on-chain commitment.
+    // Always false if no hash was committed.
+    pub fn verify_metadata(&self, document: &[u8]) -> bool {
+        self.metadata_hash != [0u8; 32] && hash(document).to_bytes() == self.metadata_hash
+    }
+
+    // Checks that `signer` may perform an action needing `permission`.
+    // The owner always can. Operators need the permission, must not have
+    // expired, and are locked out while the identity is suspended.
+    pub fn authorize(&self, signer: &Pubkey, permission: u8) -> Result<()> {
+        if *signer == self.authority {
+            return Ok(());
+        }
+
+        let operator = self.operators.iter().find(|op| op.key == *signer)
+            .ok_or(ErrorCode::OperatorNotAuthorized)?;
+
+        if self.suspended {
+            return err!(ErrorCode::IdentitySuspended);
+        }
+
+        if operator.expires_at <= Clock::get()?.unix_timestamp {
+            return err!(ErrorCode::OperatorExpired);
+        }
+
+        if operator.permissions & permission != permission {
+            return err!(ErrorCode::OperatorNotAuthorized);
+        }
+
+        Ok(())
+    }
+}
+
+// The service endpoints an identity publishes, e.g. for x402 clients that
+// need the agent's URL before paying
+#[account]
+pub struct ServiceEndpoints {
+    // The identity PDA the endpoints belong to
+    pub identity: Pubkey,
+    // Up to `MAX_ENDPOINTS` endpoints. The account is resized as entries are added and removed.
+    pub endpoints: Vec<ServiceEndpoint>,
+    // Bump
+    pub bump: u8,
+}
+
+impl ServiceEndpoints {
+    // Space = 8 (disc) + 32 (identity) + (4 + count * 241) (endpoints) + 1 (bump)
+    pub const fn space(count: usize) -> usize {
+        8 + 32 + 4 + count * ServiceEndpoint::SPACE + 1
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ServiceEndpoint {
+    pub protocol: EndpointProtocol,
+    // e.g. "https://agent.example.com/x402"
+    pub url: String,
+    // e.g. "translate"
+    pub label: String,
+}
+
+impl ServiceEndpoint {
+    // Space = 1 (protocol) + (4 + 200) (url) + (4 + 32) (label)
+    pub const SPACE: usize = 1 + 4 + MAX_URI_LENGTH + 4 + MAX_ENDPOINT_LABEL_LENGTH;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EndpointProtocol {
+    // HTTP 402 payment-gated API
+    X402,
+    // Agent2Agent
+    A2a,
+    // Model Context Protocol
+    Mcp,
+}
+
+// A mint an agent accepts, at `["payment_policy", identity, mint]`
+#[account]
+pub struct PaymentPolicy {
+    // The identity accepting the mint
+    pub identity: Pubkey,
+    // The accepted mint
+    pub mint: Pubkey,
+    // Accepted amount per payment, inclusive (in token base units)
+    pub min_amount: u64,
+    pub max_amount: u64,
+    // Bump
+    pub bump: u8,
+}
+
+impl PaymentPolicy {
+    // Space = 8 (disc) + 32 (identity) + 32 (mint) + 8 (min) + 8 (max) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 32 + 8 + 8 + 1;
+}
+
+// A service an agent sells, at `["service", identity, service_id]`
+#[account]
+pub struct ServiceOffering {
+    // The identity offering the service
+    pub identity: Pubkey,
+    // Chosen by the agent, unique per identity
+    pub service_id: u32,
+    // Link to the off-chain service description
+    pub uri: String,
+    // The only mint accepted as payment
+    pub mint: Pubkey,
+    // Accepted payment range, inclusive (in token base units)
+    pub min_price: u64,
+    pub max_price: u64,
+    // Paused listings can't be paid for
+    pub active: bool,
+    // Payments logged against this service
+    pub total_transactions: u64,
+    pub total_volume: u64,
+    // Bump
+    pub bump: u8,
+}
+
+impl ServiceOffering {
+    // Space = 8 (disc) + 32 (identity) + 4 (service id) + (4 + 200) (uri) + 32 (mint)
+    //       + 8 (min) + 8 (max) + 1 (active) + 8 (txns) + 8 (volume) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 4 + 4 + MAX_URI_LENGTH + 32 + 8 + 8 + 1 + 8 + 8 + 1;
+}
+
+// A replaced version of an identity's metadata, at
+// `["metadata_version", identity, version]`. Version `n` was current from
+// `slot` until `replaced_slot`. The current version lives on the identity.
+#[account]
+pub struct MetadataVersion {
+    pub identity: Pubkey,
+    pub version: u32,
+    pub uri: String,
+    pub metadata_hash: [u8; 32],
+    pub slot: u64,
+    pub replaced_slot: u64,
+    pub bump: u8,
+}
+
+impl MetadataVersion {
+    // Space = 8 (disc) + 32 (identity) + 4 (version) + (4 + 200) (uri) + 32 (hash)
+    //       + 8 (slot) + 8 (replaced slot) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 4 + 4 + MAX_URI_LENGTH + 32 + 8 + 8 + 1;
+}
+
+// The index of identities carrying a capability tag
+#[account]
+pub struct TagIndex {
+    pub tag: String,    // The normalized tag
+    pub count: u32,     // Number of entries, stored densely at indexes 0..count
+    pub bump: u8,
+}
+
+impl TagIndex {
+    // Space = 8 (disc) + (4 + 32) (tag) + 4 (count) + 1 (bump)
+    pub const SPACE: usize = 8 + 4 + MAX_TAG_LENGTH + 4 + 1;
+}
+
+// One slot of a tag index, at `["tag_entry", tag_index, index]`
+#[account]
+pub struct TagEntry {
+    pub tag_index: Pubkey,
+    pub identity: Pubkey,
+    pub bump: u8,
+}
+
+impl TagEntry {
+    // Space = 8 (disc) + 32 (tag index) + 32 (identity) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 32 + 1;
+}
+
+// Where an identity sits in a tag index, at `["tag_member", tag_index, identity]`
+#[account]
+pub struct TagMember {
+    pub tag_index: Pubkey,
+    pub identity: Pubkey,
+    pub index: u32,
+    pub bump: u8,
+}
+
+impl TagMember {
+    // Space = 8 (disc) + 32 (tag index) + 32 (identity) + 4 (index) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 32 + 4 + 1;
+}
+
+// What kind of entity an identity represents. Set at registration and never changed.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IdentityKind {
+    Human,
+    // Must always have at least one operator (its runtime key)
+    AiAgent,
+    // Can create organizations, which always keep at least one admin
+    Organization,
+    // Owned by a program PDA, so only registrable through CPI
+    Program,
+}
+
+impl IdentityKind {
+    // The identity NFT's on-chain metadata symbol. The registry writes it, so
+    // unlike attributes in the off-chain JSON it always matches `kind`.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            IdentityKind::Human => "HUMAN",
+            IdentityKind::AiAgent => "AIAGENT",
+            IdentityKind::Organization => "ORG",
+            IdentityKind::Program => "PROGRAM",
+        }
+    }
+}
+
+// A key allowed to act for an identity, e.g. one held by an AI agent runtime
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct Operator {
+    pub key: Pubkey,
+    // Bit set of `OPERATOR_*` permission flags
+    pub permissions: u8,
+    // Unix timestamp after which the key can no longer act
+    pub expires_at: i64,
+}
+
+impl Operator {
+    // Space = 32 (key) + 1 (permissions) + 8 (expires_at)
+    pub const SPACE: usize = 32 + 1 + 8;
+}
+
+// Counts the identities a wallet has registered. Identity `i` of a wallet
+// lives at `[b"identity", creator, i as u32 little-endian]` for `i` in `0..count`.
+// Closed identities leave gaps, and moved ones keep their address but have
+// a different `authority`.
+//
+// The identities a wallet owns now, registered or received through an
+// authority change, are listed by `OwnedIdentity` entries at
+// `[b"owned_identity", authority, i as u32 little-endian]` for `i` in
+// `0..owned_count`. Entries of closed or handed over identities are closed
+// and leave gaps.
+#[account]
+pub struct IdentityCounter {
+    // The wallet the identities belong to
+    pub authority: Pubkey,
+    // Number of identities ever registered (the next index)
+    pub count: u32,
+    // Number of `OwnedIdentity` entries ever created (the next slot)
+    pub owned_count: u32,
+    // Bump
+    pub bump: u8,
+}
+
+impl IdentityCounter {
+    // Space = 8 (disc) + 32 (authority) + 4 (count) + 4 (owned count) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 4 + 4 + 1;
+
+    // Takes the next `OwnedIdentity` slot of the wallet
+    pub fn next_owned_slot(&mut self) -> Result<u32> {
+        let slot = self.owned_count;
+        self.owned_count = self.owned_count.checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+        Ok(slot)
+    }
+}
+
+// One identity owned by a wallet, at `["owned_identity", authority, slot]`
+#[account]
+pub struct OwnedIdentity {
+    pub authority: Pubkey,
+    pub identity: Pubkey,
+    pub bump: u8,
+}
+
+impl OwnedIdentity {
+    // Space = 8 (disc) + 32 (authority) + 32 (identity) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 32 + 1;
+}
+
+// Global registry settings, created once by `initialize_collection`
+#[account]
+pub struct RegistryConfig {
+    // The registry admin (the program's upgrade authority at initialization)
+    pub admin: Pubkey,
+    // The verified Metaplex collection every identity NFT belongs to
+    pub collection_mint: Pubkey,
+    // Seconds between proposing and accepting an authority change
+    pub authority_change_delay: i64,
+    // Mints whose payments count towards reputation aggregates
+    pub canonical_mints: Vec<Pubkey>,
+    // Bump
+    pub bump: u8,
+}
+
+// Maps a normalized username back to the identity that owns it.
+// Clients derive this PDA from the username, read `identity`, and from there
+// derive the reputation PDA.
+//
+// `update_identity` also claims display names here, under their compared
+// form, so they can't be registered as usernames. Such a claim's `username`
+// differs from the identity's username.
+#[account]
+pub struct UsernameClaim {
+    // The wallet that owns the username
+    pub authority: Pubkey,
+    // The identity PDA that owns the username
+    pub identity: Pubkey,
+    // The normalized username, or the compared form of a display name
+    pub username: String,
+    // Bump
+    pub bump: u8,
+}
+
+impl UsernameClaim {
+    // Space = 8 (disc) + 32 (authority) + 32 (identity) + (4 + 32) (username) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 32 + 4 + 32 + 1;
+}
+
+// Reverse lookup from an identity NFT mint to its identity
+#[account]
+pub struct IdentityMintRecord {
+    // The identity PDA the NFT was minted for
+    pub identity: Pubkey,
+    // The wallet that owns the identity
+    pub authority: Pubkey,
+    // Bump
+    pub bump: u8,
+}
+
+// A proposed authority change, waiting for its timelock to pass
+#[account]
+pub struct PendingAuthorityChange {
+    // The identity being moved
+    pub identity: Pubkey,
+    // The authority that proposed the change
+    pub current_authority: Pubkey,
+    // The authority that can accept the change
+    pub new_authority: Pubkey,
+    // Unix timestamp after which the change can be accepted
+    pub unlock_at: i64,
+    // Bump
+    pub bump: u8,
+}
+
+// Left behind so a wallet or username can't be re-registered to wipe its
+// history. `close_identity` creates one keyed by the username claim PDA and
+// one keyed by the wallet. `accept_authority_change` creates one keyed by the
+// old wallet.
+#[account]
+pub struct Tombstone {
+    // The wallet whose identity was closed or handed over
     pub authority: Pubkey,
-    pub username: String, // e.g., "alice"
-    pub uri: String,      // e.g., "https://arweave.net/..."
+    // The username the identity had
+    pub username: String,
+    // Unix timestamp of the close or handover
+    pub closed_at: i64,
+    // Bump
     pub bump: u8,
 }
 
-// Stores the aggregate reputation for a single agent (authority)
+impl Tombstone {
+    // Space = 8 (disc) + 32 (authority) + (4 + 32) (username) + 8 (closed_at) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 4 + 32 + 8 + 1;
+}
+
+// Stores the aggregate reputation for a single agent (identity)
 #[account]
 pub struct ReputationAccount {
     // Pubkey of the agent this reputation belongs to
     pub authority: Pubkey,
-    // Total number of services/transactions completed
+    // Total number of services/transactions paid in canonical mints
     pub total_transactions: u64,
-    // Total volume of payments processed (e.g., in USDC lamports)
+    // Total volume of payments in canonical mints (e.g., in USDC lamports)
     pub total_volume: u64,
     // Total number of reviews received
     pub total_reviews: u64,
@@ -358,6 +3918,32 @@ pub struct ReputationAccount {
     pub bump: u8,
 }
 
+impl ReputationAccount {
+    // Space = 8 (disc) + 32 (auth) + 8 (txns) + 8 (vol) + 8 (reviews) + 8 (rating) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 8 + 8 + 8 + 8 + 1;
+}
+
+// An agent's payments in one mint, at `["mint_volume", identity, mint]`.
+// Kept for every mint, canonical or not.
+#[account]
+pub struct MintVolume {
+    // The identity that was paid
+    pub identity: Pubkey,
+    // The mint it was paid in
+    pub mint: Pubkey,
+    // Number of payments in this mint
+    pub total_transactions: u64,
+    // Total amount paid in this mint (in its base units)
+    pub total_volume: u64,
+    // Bump
+    pub bump: u8,
+}
+
+impl MintVolume {
+    // Space = 8 (disc) + 32 (identity) + 32 (mint) + 8 (txns) + 8 (volume) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 32 + 8 + 8 + 1;
+}
+
 // Stores a single review left by a user for an agent
 #[account]
 pub struct ReviewAccount {
@@ -372,4 +3958,187 @@ pub struct ReviewAccount {
     pub uri: String,
     // Bump
     pub bump: u8,
-}
\ No newline at end of file
+}
+
+// Records the payments a single payer has made to a single agent
+#[account]
+pub struct PaymentRecord {
+    // The agent who was paid
+    pub agent_authority: Pubkey,
+    // The user who paid
+    pub payer: Pubkey,
+    // Number of service transactions paid by this payer
+    pub total_transactions: u64,
+    // Total amount paid by this payer (in token base units)
+    pub total_amount: u64,
+    // The agent's metadata version at the latest payment
+    pub last_metadata_version: u32,
+    // Bump
+    pub bump: u8,
+}
+
+// A group of identities operating under a shared brand
+#[account]
+pub struct OrganizationAccount {
+    // The wallet that created the organization
+    pub creator: Pubkey,
+    // The normalized organization name (same rules as usernames)
+    pub name: String,
+    // Link to the organization's off-chain profile
+    pub uri: String,
+    // Number of member identities, admins included
+    pub member_count: u32,
+    // Number of members with the admin role
+    pub admin_count: u32,
+    // Sum of the members' reputation, as of their last `sync_member_reputation`
+    pub total_transactions: u64,
+    pub total_volume: u64,
+    pub total_reviews: u64,
+    pub total_rating_score: u64,
+    // Bump
+    pub bump: u8,
+}
+
+impl OrganizationAccount {
+    // Space = 8 (disc) + 32 (creator) + (4 + 32) (name) + (4 + 200) (uri) + 4 (members) + 4 (admins)
+    //       + 8 (txns) + 8 (vol) + 8 (reviews) + 8 (rating) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 4 + 32 + 4 + 200 + 4 + 4 + 8 + 8 + 8 + 8 + 1;
+
+    // Takes a membership that is being closed out of the counts and the
+    // aggregate reputation. Fails for the last admin.
+    pub fn release_membership(&mut self, membership: &OrganizationMember) -> Result<()> {
+        if membership.role == OrganizationRole::Admin {
+            if self.admin_count <= 1 {
+                return err!(ErrorCode::LastOrganizationAdmin);
+            }
+            self.admin_count -= 1;
+        }
+
+        self.member_count = self.member_count.checked_sub(1)
+            .ok_or(ErrorCode::Overflow)?;
+        self.total_transactions = self.total_transactions.checked_sub(membership.synced_transactions)
+            .ok_or(ErrorCode::Overflow)?;
+        self.total_volume = self.total_volume.checked_sub(membership.synced_volume)
+            .ok_or(ErrorCode::Overflow)?;
+        self.total_reviews = self.total_reviews.checked_sub(membership.synced_reviews)
+            .ok_or(ErrorCode::Overflow)?;
+        self.total_rating_score = self.total_rating_score.checked_sub(membership.synced_rating_score)
+            .ok_or(ErrorCode::Overflow)?;
+
+        Ok(())
+    }
+}
+
+// An identity's membership in an organization. Its existence at
+// `["org_member", organization, identity]` is the proof of membership.
+#[account]
+pub struct OrganizationMember {
+    // The organization
+    pub organization: Pubkey,
+    // The member identity
+    pub identity: Pubkey,
+    // The identity's authority when it joined. The membership's rent goes to
+    // the identity's current authority instead.
+    pub authority: Pubkey,
+    // What the member may do in the organization
+    pub role: OrganizationRole,
+    // Unix timestamp of joining
+    pub joined_at: i64,
+    // The member's reputation already counted in the organization's totals
+    pub synced_transactions: u64,
+    pub synced_volume: u64,
+    pub synced_reviews: u64,
+    pub synced_rating_score: u64,
+    // Bump
+    pub bump: u8,
+}
+
+impl OrganizationMember {
+    // Space = 8 (disc) + 32 (organization) + 32 (identity) + 32 (authority) + 1 (role)
+    //       + 8 (joined_at) + 8 (txns) + 8 (vol) + 8 (reviews) + 8 (rating) + 1 (bump)
+    pub const SPACE: usize = 8 + 32 + 32 + 32 + 1 + 8 + 8 + 8 + 8 + 8 + 1;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OrganizationRole {
+    // Can add and remove members
+    Admin,
+    Member,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &[u8] = br#"{"name":"Sol User"}"#;
+
+    fn identity_with_hash(metadata_hash: [u8; 32]) -> IdentityAccount {
+        IdentityAccount {
+            authority: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            index: 0,
+            suspended: false,
+            parent: Pubkey::default(),
+            depth: 0,
+            revoked: false,
+            kind: IdentityKind::Human,
+            creator: Pubkey::new_unique(),
+            username: "sol_user".to_string(),
+            display_name: "sol_user".to_string(),
+            uri: "https://arweave.net/my-profile-json".to_string(),
+            metadata_hash,
+            metadata_version: 0,
+            metadata_slot: 0,
+            operators: Vec::new(),
+            tags: Vec::new(),
+            service_count: 0,
+            payment_policy_count: 0,
+            org_membership_count: 0,
+            sub_identity_count: 0,
+            owned_index: 0,
+            bump: 255,
+        }
+    }
+
+    #[test]
+    fn verify_metadata_accepts_the_committed_document() {
+        let identity = identity_with_hash(hash(DOCUMENT).to_bytes());
+        assert!(identity.verify_metadata(DOCUMENT));
+    }
+
+    #[test]
+    fn verify_metadata_rejects_a_different_document() {
+        let identity = identity_with_hash(hash(DOCUMENT).to_bytes());
+        assert!(!identity.verify_metadata(br#"{"name":"Someone Else"}"#));
+    }
+
+    #[test]
+    fn verify_metadata_rejects_everything_without_a_commitment() {
+        let identity = identity_with_hash([0u8; 32]);
+        assert!(!identity.verify_metadata(DOCUMENT));
+        assert!(!identity.verify_metadata(b""));
+    }
+
+    #[test]
+    fn kind_symbols_are_valid_metaplex_symbols() {
+        for kind in [IdentityKind::Human, IdentityKind::AiAgent, IdentityKind::Organization, IdentityKind::Program] {
+            assert!(validate_symbol(kind.symbol()).is_ok());
+        }
+        assert!(validate_symbol("TOOLONGSYMBOL").is_err());
+        assert!(validate_symbol("id$").is_err());
+    }
+
+    #[test]
+    fn usernames_and_display_names_share_the_reserved_word_rule() {
+        for name in ["admin", "official_registry", "support_bot"] {
+            assert!(validate_username(name).is_err());
+            assert!(check_reserved_words(&display_name_key(name)).is_err());
+        }
+        assert!(check_reserved_words(&display_name_key("Official Registry")).is_err());
+
+        // A display name that restyles a valid username is always allowed
+        let username = validate_username("display_agent").unwrap();
+        assert!(check_reserved_words(&display_name_key("Display.Agent")).is_ok());
+        assert_eq!(display_name_key("Display.Agent"), username);
+    }
+}
@@ -0,0 +1,391 @@
+use anchor_lang::prelude::*;
+use identity_register::{
+    cpi::accounts::{InitializeReputation, ProposeAuthorityChange, RegisterIdentity, SetPaymentPolicy},
+    program::IdentityRegister,
+    IdentityKind,
+};
+
+// This is your program's unique ID. Get it after you build/deploy.
+declare_id!("D2UTRqy6FhCXMxE2RAgG8VpDX9nYE24ywLj6uvC9MyQL");
+
+// Stands in for a vault, DAO or agent-runtime program that registers itself
+// as an agent. The agent is the program's `vault` PDA, which signs every
+// call into the identity registry with `invoke_signed`.
+#[program]
+pub mod mock_caller {
+    use super::*;
+
+    pub fn register_vault_identity(
+        ctx: Context<RegisterVaultIdentity>,
+        username: String,
+        uri: String,
+        metadata_hash: [u8; 32],
+    ) -> Result<()> {
+        let owner = ctx.accounts.owner.key();
+        let vault_seeds: &[&[&[u8]]] = &[&[b"vault", owner.as_ref(), &[ctx.bumps.vault]]];
+
+        identity_register::cpi::register_identity(
+            CpiContext::new_with_signer(
+                ctx.accounts.identity_register_program.to_account_info(),
+                RegisterIdentity {
+                    identity_counter: ctx.accounts.identity_counter.to_account_info(),
+                    owned_identity: ctx.accounts.owned_identity.to_account_info(),
+                    identity_account: ctx.accounts.identity_account.to_account_info(),
+                    tombstone: ctx.accounts.tombstone.to_account_info(),
+                    username_claim: ctx.accounts.username_claim.to_account_info(),
+                    username_tombstone: ctx.accounts.username_tombstone.to_account_info(),
+                    authority: ctx.accounts.vault.to_account_info(),
+                    payer: ctx.accounts.owner.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    registry_authority: ctx.accounts.registry_authority.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    token_account: ctx.accounts.token_account.to_account_info(),
+                    identity_mint_record: ctx.accounts.identity_mint_record.to_account_info(),
+                    metadata_account: ctx.accounts.metadata_account.to_account_info(),
+                    master_edition_account: ctx.accounts.master_edition_account.to_account_info(),
+                    config: ctx.accounts.config.to_account_info(),
+                    collection_mint: ctx.accounts.collection_mint.to_account_info(),
+                    collection_metadata: ctx.accounts.collection_metadata.to_account_info(),
+                    collection_master_edition: ctx.accounts.collection_master_edition.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    associated_token_program: ctx.accounts.associated_token_program.to_account_info(),
+                    token_metadata_program: ctx.accounts.token_metadata_program.to_account_info(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+                vault_seeds,
+            ),
+            username,
+            uri,
+            metadata_hash,
+            IdentityKind::Program,
+            None,
+        )?;
+
+        msg!("Vault {} registered as an agent", ctx.accounts.vault.key());
+        Ok(())
+    }
+
+    // Forwards a `Program` identity registration for a plain wallet. The
+    // registry must reject it, since a wallet is not a program PDA even when
+    // its registration arrives through CPI.
+    pub fn forward_program_registration(
+        ctx: Context<ForwardProgramRegistration>,
+        username: String,
+        uri: String,
+        metadata_hash: [u8; 32],
+    ) -> Result<()> {
+        identity_register::cpi::register_identity(
+            CpiContext::new(
+                ctx.accounts.identity_register_program.to_account_info(),
+                RegisterIdentity {
+                    identity_counter: ctx.accounts.identity_counter.to_account_info(),
+                    owned_identity: ctx.accounts.owned_identity.to_account_info(),
+                    identity_account: ctx.accounts.identity_account.to_account_info(),
+                    tombstone: ctx.accounts.tombstone.to_account_info(),
+                    username_claim: ctx.accounts.username_claim.to_account_info(),
+                    username_tombstone: ctx.accounts.username_tombstone.to_account_info(),
+                    authority: ctx.accounts.wallet.to_account_info(),
+                    payer: ctx.accounts.wallet.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    registry_authority: ctx.accounts.registry_authority.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    token_account: ctx.accounts.token_account.to_account_info(),
+                    identity_mint_record: ctx.accounts.identity_mint_record.to_account_info(),
+                    metadata_account: ctx.accounts.metadata_account.to_account_info(),
+                    master_edition_account: ctx.accounts.master_edition_account.to_account_info(),
+                    config: ctx.accounts.config.to_account_info(),
+                    collection_mint: ctx.accounts.collection_mint.to_account_info(),
+                    collection_metadata: ctx.accounts.collection_metadata.to_account_info(),
+                    collection_master_edition: ctx.accounts.collection_master_edition.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    associated_token_program: ctx.accounts.associated_token_program.to_account_info(),
+                    token_metadata_program: ctx.accounts.token_metadata_program.to_account_info(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+            ),
+            username,
+            uri,
+            metadata_hash,
+            IdentityKind::Program,
+            None,
+        )?;
+
+        msg!("Wallet {} registered as a program", ctx.accounts.wallet.key());
+        Ok(())
+    }
+
+    pub fn initialize_vault_reputation(ctx: Context<InitializeVaultReputation>) -> Result<()> {
+        let owner = ctx.accounts.owner.key();
+        let vault_seeds: &[&[&[u8]]] = &[&[b"vault", owner.as_ref(), &[ctx.bumps.vault]]];
+
+        identity_register::cpi::initialize_reputation(CpiContext::new_with_signer(
+            ctx.accounts.identity_register_program.to_account_info(),
+            InitializeReputation {
+                authority: ctx.accounts.vault.to_account_info(),
+                payer: ctx.accounts.owner.to_account_info(),
+                identity_account: ctx.accounts.identity_account.to_account_info(),
+                reputation_account: ctx.accounts.reputation_account.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            },
+            vault_seeds,
+        ))?;
+
+        msg!("Vault {} reputation initialized", ctx.accounts.vault.key());
+        Ok(())
+    }
+
+    pub fn set_vault_payment_policy(ctx: Context<SetVaultPaymentPolicy>, min_amount: u64, max_amount: u64) -> Result<()> {
+        let owner = ctx.accounts.owner.key();
+        let vault_seeds: &[&[&[u8]]] = &[&[b"vault", owner.as_ref(), &[ctx.bumps.vault]]];
+
+        identity_register::cpi::set_payment_policy(
+            CpiContext::new_with_signer(
+                ctx.accounts.identity_register_program.to_account_info(),
+                SetPaymentPolicy {
+                    signer: ctx.accounts.vault.to_account_info(),
+                    payer: ctx.accounts.owner.to_account_info(),
+                    identity_account: ctx.accounts.identity_account.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    payment_policy: ctx.accounts.payment_policy.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                },
+                vault_seeds,
+            ),
+            min_amount,
+            max_amount,
+        )?;
+
+        msg!("Vault {} accepts {}", ctx.accounts.vault.key(), ctx.accounts.mint.key());
+        Ok(())
+    }
+
+    pub fn propose_vault_authority_change(ctx: Context<ProposeVaultAuthorityChange>, new_authority: Pubkey) -> Result<()> {
+        let owner = ctx.accounts.owner.key();
+        let vault_seeds: &[&[&[u8]]] = &[&[b"vault", owner.as_ref(), &[ctx.bumps.vault]]];
+
+        identity_register::cpi::propose_authority_change(
+            CpiContext::new_with_signer(
+                ctx.accounts.identity_register_program.to_account_info(),
+                ProposeAuthorityChange {
+                    authority: ctx.accounts.vault.to_account_info(),
+                    identity_account: ctx.accounts.identity_account.to_account_info(),
+                    config: ctx.accounts.config.to_account_info(),
+                    pending_authority_change: ctx.accounts.pending_authority_change.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                },
+                vault_seeds,
+            ),
+            new_authority,
+        )?;
+
+        msg!("Vault {} proposed {} as its new authority", ctx.accounts.vault.key(), new_authority);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct RegisterVaultIdentity<'info> {
+    // The vault's owner, who pays for the registration
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    /// CHECK: PDA used only as a signer. It is the agent's authority.
+    #[account(
+        seeds = [b"vault", owner.key().as_ref()],
+        bump
+    )]
+    pub vault: UncheckedAccount<'info>,
+
+    // --- Identity registry accounts, validated by the registry ---
+
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub identity_counter: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub owned_identity: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub identity_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub tombstone: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub username_claim: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub username_tombstone: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub registry_authority: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub mint: Signer<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub token_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub identity_mint_record: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub metadata_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub master_edition_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub config: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub collection_mint: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub collection_metadata: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub collection_master_edition: UncheckedAccount<'info>,
+
+    // --- Required Programs ---
+
+    pub identity_register_program: Program<'info, IdentityRegister>,
+    /// CHECK: Validated by the identity registry
+    pub token_program: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub associated_token_program: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub token_metadata_program: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct ForwardProgramRegistration<'info> {
+    // A plain wallet posing as a program identity. Pays for the registration.
+    #[account(mut)]
+    pub wallet: Signer<'info>,
+
+    // --- Identity registry accounts, validated by the registry ---
+
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub identity_counter: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub owned_identity: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub identity_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub tombstone: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub username_claim: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub username_tombstone: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub registry_authority: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub mint: Signer<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub token_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub identity_mint_record: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub metadata_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub master_edition_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub config: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub collection_mint: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub collection_metadata: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub collection_master_edition: UncheckedAccount<'info>,
+
+    // --- Required Programs ---
+
+    pub identity_register_program: Program<'info, IdentityRegister>,
+    /// CHECK: Validated by the identity registry
+    pub token_program: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub associated_token_program: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub token_metadata_program: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeVaultReputation<'info> {
+    // The vault's owner, who pays for the reputation account
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    /// CHECK: PDA used only as a signer. It is the agent's authority.
+    #[account(
+        seeds = [b"vault", owner.key().as_ref()],
+        bump
+    )]
+    pub vault: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the identity registry
+    pub identity_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub reputation_account: UncheckedAccount<'info>,
+
+    pub identity_register_program: Program<'info, IdentityRegister>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetVaultPaymentPolicy<'info> {
+    // The vault's owner, who pays for the policy account
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    /// CHECK: PDA used only as a signer. It is the agent's authority.
+    #[account(
+        seeds = [b"vault", owner.key().as_ref()],
+        bump
+    )]
+    pub vault: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub identity_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub payment_policy: UncheckedAccount<'info>,
+
+    pub identity_register_program: Program<'info, IdentityRegister>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeVaultAuthorityChange<'info> {
+    pub owner: Signer<'info>,
+
+    /// CHECK: PDA used only as a signer. It is the agent's authority, and
+    /// pays for the pending change, so it must hold enough lamports.
+    #[account(
+        mut,
+        seeds = [b"vault", owner.key().as_ref()],
+        bump
+    )]
+    pub vault: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the identity registry
+    pub identity_account: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    pub config: UncheckedAccount<'info>,
+    /// CHECK: Validated by the identity registry
+    #[account(mut)]
+    pub pending_authority_change: UncheckedAccount<'info>,
+
+    pub identity_register_program: Program<'info, IdentityRegister>,
+    pub system_program: Program<'info, System>,
+}
@@ -2,11 +2,15 @@ import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { IdentityRegister } from "../target/types/identity_register";
 import { assert } from "chai";
+import { createHash } from "crypto";
 import { 
   getOrCreateAssociatedTokenAccount, 
+  getAssociatedTokenAddressSync,
   createMint, 
   mintTo, 
-  getAccount 
+  transfer,
+  getAccount,
+  getMint
 } from "@solana/spl-token";
 
 describe("identity_register", () => {
@@ -26,25 +30,92 @@ describe("identity_register", () => {
   const testUsername = "sol_user_123";
   const testSymbol = "IDENTITY";
   const testUri = "https://arweave.net/my-profile-json";
+  const testMetadataHash = Array.from(createHash("sha256").update('{"name":"Sol User"}').digest());
 
-  // Calculate the PDA for the authority's identity account
-  const [identityPda] = anchor.web3.PublicKey.findProgramAddressSync(
-    [
-      Buffer.from("identity"), 
-      authority.toBuffer()
-    ],
-    program.programId
-  );
+  // Calculate the PDA for a wallet's identity account. A wallet can own several
+  // identities, numbered from 0 (the index is a little-endian u32 seed).
+  const identityPdaFor = (owner: anchor.web3.PublicKey, index = 0) => {
+    const indexSeed = Buffer.alloc(4);
+    indexSeed.writeUInt32LE(index);
+    return anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("identity"), owner.toBuffer(), indexSeed],
+      program.programId
+    )[0];
+  };
+
+  // Calculate the PDA listing one of the identities a wallet owns now, by slot
+  const ownedIdentityPdaFor = (owner: anchor.web3.PublicKey, slot = 0) => {
+    const slotSeed = Buffer.alloc(4);
+    slotSeed.writeUInt32LE(slot);
+    return anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("owned_identity"), owner.toBuffer(), slotSeed],
+      program.programId
+    )[0];
+  };
+
+  // The entry the wallet's next registered or received identity is listed at
+  const nextOwnedIdentityPda = async (owner: anchor.web3.PublicKey) => {
+    const [counterPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("identity_counter"), owner.toBuffer()],
+      program.programId
+    );
+    const counter = await program.account.identityCounter.fetchNullable(counterPda);
+    return ownedIdentityPdaFor(owner, counter ? counter.ownedCount : 0);
+  };
+
+  // Calculate the PDA for an identity's reputation account
+  const reputationPdaFor = (identity: anchor.web3.PublicKey) =>
+    anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), identity.toBuffer()],
+      program.programId
+    )[0];
+
+  // Calculate the PDA archiving version `version` of an identity's metadata
+  const metadataVersionPdaFor = (identity: anchor.web3.PublicKey, version: number) => {
+    const versionSeed = Buffer.alloc(4);
+    versionSeed.writeUInt32LE(version);
+    return anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("metadata_version"), identity.toBuffer(), versionSeed],
+      program.programId
+    )[0];
+  };
+
+  // Calculate the PDA holding an identity's payment rules for a mint
+  const paymentPolicyPdaFor = (identity: anchor.web3.PublicKey, mint: anchor.web3.PublicKey) =>
+    anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("payment_policy"), identity.toBuffer(), mint.toBuffer()],
+      program.programId
+    )[0];
+
+  // The authority's first identity and its reputation account
+  const identityPda = identityPdaFor(authority);
+  const reputationPda = reputationPdaFor(identityPda);
 
-  // Calculate the PDA for the reputation account
-  const [reputationPda] = anchor.web3.PublicKey.findProgramAddressSync(
-    [
-      Buffer.from("reputation"), 
-      authority.toBuffer()
-    ],
-    program.programId
+  const TOKEN_METADATA_PROGRAM_ID = new anchor.web3.PublicKey(
+    "metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s"
   );
 
+  // Calculate the PDA for a username claim (seeded by the hash of the normalized username)
+  const usernameClaimPda = (username: string) =>
+    anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("username"),
+        createHash("sha256").update(username.trim().toLowerCase()).digest()
+      ],
+      program.programId
+    )[0];
+
+  // The username claim a display name is checked against ("Sol User" -> "sol_user")
+  const displayNameClaimPda = (displayName: string) =>
+    usernameClaimPda(displayName.trim().toLowerCase().replace(/[\s.-]/g, "_"));
+
+  // The identity NFT mint created in test 1
+  let identityMint: anchor.web3.PublicKey;
+
+  // The registry collection mint created in the 'before' block
+  const collectionMintKeypair = anchor.web3.Keypair.generate();
+  const collectionMint = collectionMintKeypair.publicKey;
+
   let mockUsdcMint: anchor.web3.PublicKey;
   let payerUser: anchor.web3.Keypair;
   let payerTokenAccount: anchor.web3.PublicKey;
@@ -67,6 +138,73 @@ describe("identity_register", () => {
     });
   };
 
+  // --- Helper to register an identity and reputation account for a new user ---
+  const registerAgent = async (user: anchor.web3.Keypair, username: string, kind: any = { human: {} }, index = 0) => {
+    const mintKeypair = anchor.web3.Keypair.generate();
+    await program.methods
+      .registerIdentity(username, testUri, testMetadataHash, kind, null)
+      .accounts({
+        authority: user.publicKey,
+        payer: user.publicKey,
+        identityAccount: identityPdaFor(user.publicKey, index),
+        ownedIdentity: await nextOwnedIdentityPda(user.publicKey),
+        mint: mintKeypair.publicKey,
+        usernameClaim: usernameClaimPda(username),
+        collectionMint: collectionMint,
+      })
+      .signers([user, mintKeypair])
+      .rpc();
+
+    const userIdentityPda = identityPdaFor(user.publicKey, index);
+    const userReputationPda = reputationPdaFor(userIdentityPda);
+
+    await program.methods
+      .initializeReputation()
+      .accounts({
+        authority: user.publicKey,
+        payer: user.publicKey,
+        identityAccount: userIdentityPda,
+        reputationAccount: userReputationPda,
+      })
+      .signers([user])
+      .rpc();
+
+    return { mint: mintKeypair.publicKey, identityPda: userIdentityPda, reputationPda: userReputationPda };
+  };
+
+  // --- Helper to move an agent registered with `registerAgent` to a new key, with no timelock ---
+  const changeAuthority = async (
+    oldKey: anchor.web3.Keypair,
+    newKey: anchor.web3.Keypair,
+    username: string,
+    agent: { mint: anchor.web3.PublicKey, identityPda: anchor.web3.PublicKey, reputationPda: anchor.web3.PublicKey }
+  ) => {
+    await program.methods
+      .setAuthorityChangeDelay(new anchor.BN(0))
+      .accounts({ admin: authority } as any)
+      .rpc();
+
+    await program.methods
+      .proposeAuthorityChange(newKey.publicKey)
+      .accounts({ authority: oldKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([oldKey])
+      .rpc();
+
+    await program.methods
+      .acceptAuthorityChange(null)
+      .accounts({
+        newAuthority: newKey.publicKey,
+        oldAuthority: oldKey.publicKey,
+        identityAccount: agent.identityPda,
+        newOwnedIdentity: await nextOwnedIdentityPda(newKey.publicKey),
+        reputationAccount: agent.reputationPda,
+        usernameClaim: usernameClaimPda(username),
+        mint: agent.mint,
+      } as any)
+      .signers([newKey])
+      .rpc();
+  };
+
   // This 'before' block runs once before all tests
   // We use it to set up our mock token and accounts
   before(async () => {
@@ -114,6 +252,22 @@ describe("identity_register", () => {
       1000 * 1_000_000 // 1000 USDC
     );
     console.log("Minted 1000 USDC to payer");
+
+    // 6. Create the registry collection. Only the program's upgrade authority can do this.
+    const [programData] = anchor.web3.PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      new anchor.web3.PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+    );
+    await program.methods
+      .initializeCollection("Identity Register", testSymbol, "https://arweave.net/collection-json")
+      .accounts({
+        admin: authority,
+        programData: programData,
+        collectionMint: collectionMint,
+      } as any)
+      .signers([collectionMintKeypair])
+      .rpc();
+    console.log(`Identity collection: ${collectionMint.toBase58()}`);
   });
 
   it("1. Registers a new identity!", async () => {
@@ -123,15 +277,21 @@ describe("identity_register", () => {
     // --- Act ---
     // Call the `registerIdentity` instruction
     const tx = await program.methods
-      .registerIdentity(testUsername, testSymbol, testUri)
+      .registerIdentity(testUsername, testUri, testMetadataHash, { human: {} }, null)
       .accounts({
         authority: authority,
+        payer: authority,
+        identityAccount: identityPda,
+        ownedIdentity: await nextOwnedIdentityPda(authority),
         mint: mintKeypair.publicKey,
+        usernameClaim: usernameClaimPda(testUsername),
+        collectionMint: collectionMint,
       })
       .signers([mintKeypair])
       .rpc();
     
     console.log("Your transaction signature", tx);
+    identityMint = mintKeypair.publicKey;
 
     // --- Assert ---
     // Fetch the newly created account
@@ -140,44 +300,61 @@ describe("identity_register", () => {
     // Check if the data was stored correctly
     assert.ok(accountData.authority.equals(authority), "Authority mismatch");
     assert.strictEqual(accountData.username, testUsername, "Username mismatch");
+    assert.strictEqual(accountData.displayName, testUsername, "Display name mismatch");
     assert.strictEqual(accountData.uri, testUri, "URI mismatch");
+    assert.deepEqual(accountData.metadataHash, testMetadataHash, "Metadata hash mismatch");
+    assert.ok(accountData.mint.equals(mintKeypair.publicKey), "Mint mismatch");
+
+    // The NFT mint resolves back to the identity
+    const [identityMintPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("identity_mint"), mintKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const mintRecord = await program.account.identityMintRecord.fetch(identityMintPda);
+    assert.ok(mintRecord.identity.equals(identityPda), "Mint record identity mismatch");
+    assert.ok(mintRecord.authority.equals(authority), "Mint record authority mismatch");
 
     console.log("✅ Identity registered successfully!");
     console.log("  Username:", accountData.username);
     console.log("  NFT Mint:", mintKeypair.publicKey.toBase58());
   });
 
-  it("2. Fails to register a duplicate identity!", async () => {
+  it("2. Fails to overwrite an existing identity!", async () => {
     // Generate another mint keypair
     const mintKeypair2 = anchor.web3.Keypair.generate();
 
     // --- Act & Assert ---
-    // Try to call the same instruction again for the same user
+    // Try to register again for the same user into its first identity
     try {
       await program.methods
-        .registerIdentity("new_username", "NEW_SYM", "new_uri") // Different data
+        .registerIdentity("new_username", "new_uri", testMetadataHash, { human: {} }, null) // Different data
         .accounts({
           authority: authority,
+          payer: authority,
+          identityAccount: identityPda, // The next free index is 1
+          ownedIdentity: await nextOwnedIdentityPda(authority),
           mint: mintKeypair2.publicKey,
+          usernameClaim: usernameClaimPda("new_username"),
+          collectionMint: collectionMint,
         })
         .signers([mintKeypair2])
         .rpc();
       
       // If the above doesn't throw an error, force the test to fail
-      assert.fail("Transaction should have failed (account already initialized)!");
+      assert.fail("Transaction should have failed (identity index already used)!");
 
     } catch (err) {
       // We expect an error, so this is a pass.
-      // Anchor will throw an error because the account PDA is already in use.
-      assert.include(err.message, "already in use", "Expected 'already in use' error");
-      console.log("✅ Correctly prevented duplicate identity registration");
+      // New identities can only be created at the wallet's next free index.
+      assert.include(err.message, "ConstraintSeeds", "Expected 'ConstraintSeeds' error");
+      console.log("✅ Correctly prevented overwriting an existing identity");
     }
   });
 
   it("3. Fails when username is too long!", async () => {
     // --- Arrange ---
-    // Create a username that is 51 characters (limit is 50)
-    const longUsername = "a".repeat(51);
+    // Create a username that is 33 characters (limit is 32, the Metaplex name limit)
+    const longUsername = "a".repeat(33);
 
     // We need a new user for this test, as the default user's PDA is already created.
     const newUser = anchor.web3.Keypair.generate();
@@ -189,10 +366,15 @@ describe("identity_register", () => {
     // --- Act & Assert ---
     try {
       await program.methods
-        .registerIdentity(longUsername, testSymbol, testUri)
+        .registerIdentity(longUsername, testUri, testMetadataHash, { human: {} }, null)
         .accounts({
           authority: newUser.publicKey,
+          payer: newUser.publicKey,
+          identityAccount: identityPdaFor(newUser.publicKey),
+          ownedIdentity: await nextOwnedIdentityPda(newUser.publicKey),
           mint: mintKeypair3.publicKey,
+          usernameClaim: usernameClaimPda(longUsername),
+          collectionMint: collectionMint,
         })
         .signers([newUser, mintKeypair3]) // Both the new user and mint must sign
         .rpc();
@@ -238,10 +420,15 @@ describe("identity_register", () => {
     // --- Act & Assert ---
     try {
       await program.methods
-        .registerIdentity(testUsername, testSymbol, longUri) // Use the long URI
+        .registerIdentity(testUsername, longUri, testMetadataHash, { human: {} }, null) // Use the long URI
         .accounts({
           authority: newUser2.publicKey,
+          payer: newUser2.publicKey,
+          identityAccount: identityPdaFor(newUser2.publicKey),
+          ownedIdentity: await nextOwnedIdentityPda(newUser2.publicKey),
           mint: mintKeypair4.publicKey,
+          usernameClaim: usernameClaimPda(testUsername),
+          collectionMint: collectionMint,
         })
         .signers([newUser2, mintKeypair4])
         .rpc();
@@ -266,6 +453,7 @@ describe("identity_register", () => {
       .initializeReputation()
       .accounts({
         authority: authority,
+        payer: authority,
         identityAccount: identityPda, // Pass the existing identity PDA
         reputationAccount: reputationPda, // Pass the new PDA to be created
       })
@@ -295,6 +483,7 @@ describe("identity_register", () => {
         .initializeReputation()
         .accounts({
           authority: authority,
+          payer: authority,
           identityAccount: identityPda,
           reputationAccount: reputationPda,
         })
@@ -315,14 +504,8 @@ describe("identity_register", () => {
     await airdrop(newUser3.publicKey);
 
     // Calculate PDAs for this new user
-    const [newUserIdentityPda] = anchor.web3.PublicKey.findProgramAddressSync(
-      [Buffer.from("identity"), newUser3.publicKey.toBuffer()],
-      program.programId
-    );
-    const [newUserReputationPda] = anchor.web3.PublicKey.findProgramAddressSync(
-      [Buffer.from("reputation"), newUser3.publicKey.toBuffer()],
-      program.programId
-    );
+    const newUserIdentityPda = identityPdaFor(newUser3.publicKey);
+    const newUserReputationPda = reputationPdaFor(newUserIdentityPda);
 
     // --- Act & Assert ---
     try {
@@ -330,6 +513,7 @@ describe("identity_register", () => {
         .initializeReputation()
         .accounts({
           authority: newUser3.publicKey,
+          payer: newUser3.publicKey,
           identityAccount: newUserIdentityPda, // This PDA *does not exist*
           reputationAccount: newUserReputationPda,
         })
@@ -352,6 +536,24 @@ describe("identity_register", () => {
 
   it("8. Logs a service transaction successfully!", async () => {
     // --- Arrange ---
+    // The agent accepts mock USDC, from 1 base unit up to 1000 USDC per payment
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(1), new anchor.BN(1000 * 1_000_000))
+      .accounts({
+        signer: authority,
+        payer: authority,
+        identityAccount: identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: paymentPolicyPdaFor(identityPda, mockUsdcMint),
+      } as any)
+      .rpc();
+
+    // Mock USDC counts towards reputation aggregates
+    await program.methods
+      .addCanonicalMint(mockUsdcMint)
+      .accounts({ admin: authority } as any)
+      .rpc();
+
     // Fetch pre-transaction state
     const repAccountPre = await program.account.reputationAccount.fetch(reputationPda);
     const payerTokenPre = await getAccount(provider.connection, payerTokenAccount);
@@ -367,7 +569,9 @@ describe("identity_register", () => {
       .accounts({
         payer: payerUser.publicKey,
         authority: authority, // Needed so Anchor knows which agent to pay
+        identityAccount: identityPda, // And which of its identities
         mint: mockUsdcMint,
+        paymentPolicy: paymentPolicyPdaFor(identityPda, mockUsdcMint),
       } as any) 
       .signers([payerUser]) // The payer must sign
       .rpc();
@@ -383,6 +587,14 @@ describe("identity_register", () => {
     assert.equal(repAccountPost.totalTransactions.toNumber(), expectedTxns, "Total transactions did not increment");
     assert.ok(repAccountPost.totalVolume.eq(expectedVolume), "Total volume did not update correctly");
 
+    const [mintVolumePda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("mint_volume"), identityPda.toBuffer(), mockUsdcMint.toBuffer()],
+      program.programId
+    );
+    const mintVolume = await program.account.mintVolume.fetch(mintVolumePda);
+    assert.equal(mintVolume.totalTransactions.toNumber(), 1, "Mint transactions not recorded");
+    assert.ok(mintVolume.totalVolume.eq(transactionAmount), "Mint volume not recorded");
+
     // 2. Check token balances
     const payerTokenPost = await getAccount(provider.connection, payerTokenAccount);
     const agentTokenPost = await getAccount(provider.connection, agentTokenAccount);
@@ -400,4 +612,3257 @@ describe("identity_register", () => {
     console.log(`  Agent Balance: ${agentTokenPost.amount.toString()}`);
   });
 
-});
\ No newline at end of file
+  it("9. Fails when the rating is out of range!", async () => {
+    // payerUser has paid the agent in test 8, so only the rating is invalid
+    try {
+      await program.methods
+        .submitReview(6, "https://arweave.net/my-review-json")
+        .accounts({
+          reviewer: payerUser.publicKey,
+          authority: authority,
+          identityAccount: identityPda,
+        } as any)
+        .signers([payerUser])
+        .rpc();
+
+      assert.fail("Transaction should have failed (invalid rating)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error.errorCode.code,
+        "InvalidRating",
+        `Expected program error 'InvalidRating', got: ${JSON.stringify(err.error)}`
+      );
+      console.log("✅ Correctly rejected out-of-range rating");
+    }
+  });
+
+  it("10. Submits a review for an agent!", async () => {
+    // --- Arrange ---
+    const rating = 5;
+    const reviewUri = "https://arweave.net/my-review-json";
+    const [reviewPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("review"), identityPda.toBuffer(), payerUser.publicKey.toBuffer()],
+      program.programId
+    );
+    const [paymentPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("payment"), identityPda.toBuffer(), payerUser.publicKey.toBuffer()],
+      program.programId
+    );
+    const repAccountPre = await program.account.reputationAccount.fetch(reputationPda);
+
+    // The payment record was created by test 8
+    const paymentData = await program.account.paymentRecord.fetch(paymentPda);
+    assert.strictEqual(paymentData.totalTransactions.toNumber(), 1, "Payment record not created");
+    assert.ok(paymentData.totalAmount.eq(transactionAmount), "Payment amount mismatch");
+    assert.equal(paymentData.lastMetadataVersion, 0, "Metadata version not recorded");
+
+    // --- Act ---
+    const tx = await program.methods
+      .submitReview(rating, reviewUri)
+      .accounts({
+        reviewer: payerUser.publicKey,
+        authority: authority,
+        identityAccount: identityPda,
+      } as any)
+      .signers([payerUser])
+      .rpc();
+
+    console.log("Your transaction signature", tx);
+
+    // --- Assert ---
+    const reviewData = await program.account.reviewAccount.fetch(reviewPda);
+    assert.ok(reviewData.agentAuthority.equals(authority), "Agent mismatch");
+    assert.ok(reviewData.reviewer.equals(payerUser.publicKey), "Reviewer mismatch");
+    assert.strictEqual(reviewData.rating, rating, "Rating mismatch");
+    assert.strictEqual(reviewData.uri, reviewUri, "URI mismatch");
+
+    const repAccountPost = await program.account.reputationAccount.fetch(reputationPda);
+    assert.strictEqual(
+      repAccountPost.totalReviews.toNumber(),
+      repAccountPre.totalReviews.toNumber() + 1,
+      "Total reviews did not increment"
+    );
+    assert.strictEqual(
+      repAccountPost.totalRatingScore.toNumber(),
+      repAccountPre.totalRatingScore.toNumber() + rating,
+      "Total rating score did not update correctly"
+    );
+
+    console.log("✅ Review submitted successfully!");
+  });
+
+  it("11. Fails to submit a second review from the same reviewer!", async () => {
+    try {
+      await program.methods
+        .submitReview(1, "https://arweave.net/another-review")
+        .accounts({
+          reviewer: payerUser.publicKey,
+          authority: authority,
+          identityAccount: identityPda,
+        } as any)
+        .signers([payerUser])
+        .rpc();
+
+      assert.fail("Transaction should have failed (review already exists)!");
+
+    } catch (err) {
+      assert.include(err.message, "already in use", "Expected 'already in use' error");
+      console.log("✅ Correctly prevented duplicate review");
+    }
+  });
+
+  it("12. Fails to review an agent without a prior payment!", async () => {
+    const newReviewer = anchor.web3.Keypair.generate();
+    await airdrop(newReviewer.publicKey);
+
+    try {
+      await program.methods
+        .submitReview(5, "https://arweave.net/fake-review")
+        .accounts({
+          reviewer: newReviewer.publicKey,
+          authority: authority,
+          identityAccount: identityPda,
+        } as any)
+        .signers([newReviewer])
+        .rpc();
+
+      assert.fail("Transaction should have failed (no payment record)!");
+
+    } catch (err: any) {
+      // The payment record PDA for this reviewer was never created
+      assert.equal(
+        err.error?.errorCode?.code,
+        "NoPaidTransaction",
+        `Expected program error 'NoPaidTransaction', got: ${JSON.stringify(err.error)}`
+      );
+      console.log("✅ Correctly rejected review without a paid transaction");
+    }
+  });
+
+  it("13. Fails to register a username that is already taken!", async () => {
+    // --- Arrange ---
+    // Same name as test 1, differing only in case and whitespace
+    const takenUsername = ` ${testUsername.toUpperCase()} `;
+    const newUser = anchor.web3.Keypair.generate();
+    await airdrop(newUser.publicKey);
+    const mintKeypair = anchor.web3.Keypair.generate();
+
+    // The claim resolves back to the original owner
+    const claimData = await program.account.usernameClaim.fetch(usernameClaimPda(takenUsername));
+    assert.ok(claimData.authority.equals(authority), "Claim authority mismatch");
+    assert.ok(claimData.identity.equals(identityPda), "Claim identity mismatch");
+    assert.strictEqual(claimData.username, testUsername, "Claim username mismatch");
+
+    // --- Act & Assert ---
+    try {
+      await program.methods
+        .registerIdentity(takenUsername, testUri, testMetadataHash, { human: {} }, null)
+        .accounts({
+          authority: newUser.publicKey,
+          payer: newUser.publicKey,
+          identityAccount: identityPdaFor(newUser.publicKey),
+          ownedIdentity: await nextOwnedIdentityPda(newUser.publicKey),
+          mint: mintKeypair.publicKey,
+          usernameClaim: usernameClaimPda(takenUsername),
+          collectionMint: collectionMint,
+        })
+        .signers([newUser, mintKeypair])
+        .rpc();
+
+      assert.fail("Transaction should have failed (username taken)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error.errorCode.code,
+        "UsernameTaken",
+        `Expected program error 'UsernameTaken', got: ${JSON.stringify(err.error)}`
+      );
+      console.log("✅ Correctly rejected a username that is already taken");
+    }
+  });
+
+  it("14. Rejects usernames that break the naming rules!", async () => {
+    const newUser = anchor.web3.Keypair.generate();
+    await airdrop(newUser.publicKey);
+
+    const cases = [
+      { username: "bad name!", code: "InvalidUsernameCharacter" },
+      { username: "Admin", code: "ReservedUsername" },
+      { username: "official_registry", code: "ReservedUsername" },
+      { username: "support_bot", code: "ReservedUsername" },
+      { username: "ab", code: "UsernameTooShort" },
+    ];
+
+    for (const { username, code } of cases) {
+      const mintKeypair = anchor.web3.Keypair.generate();
+      try {
+        await program.methods
+          .registerIdentity(username, testUri, testMetadataHash, { human: {} }, null)
+          .accounts({
+            authority: newUser.publicKey,
+            payer: newUser.publicKey,
+            identityAccount: identityPdaFor(newUser.publicKey),
+            ownedIdentity: await nextOwnedIdentityPda(newUser.publicKey),
+            mint: mintKeypair.publicKey,
+            usernameClaim: usernameClaimPda(username),
+            collectionMint: collectionMint,
+          })
+          .signers([newUser, mintKeypair])
+          .rpc();
+
+        assert.fail(`Transaction should have failed (${code})!`);
+
+      } catch (err: any) {
+        assert.equal(
+          err.error?.errorCode?.code,
+          code,
+          `Expected program error '${code}', got: ${JSON.stringify(err.error)}`
+        );
+      }
+    }
+
+    console.log("✅ Correctly rejected invalid usernames");
+  });
+
+  it("15. Updates the identity URI and display name!", async () => {
+    // --- Arrange ---
+    const newUri = "https://arweave.net/my-new-profile-json";
+    const newDocument = '{"name":"Sol User","description":"Updated profile"}';
+    const newMetadataHash = Array.from(createHash("sha256").update(newDocument).digest());
+    const newDisplayName = "Sol User";
+
+    // --- Act ---
+    const tx = await program.methods
+      .updateIdentity(newUri, newMetadataHash, newDisplayName)
+      .accounts({
+        signer: authority,
+        identityAccount: identityPda,
+        displayNameClaim: displayNameClaimPda(newDisplayName),
+        metadataVersion: metadataVersionPdaFor(identityPda, 0),
+        mint: identityMint,
+      } as any)
+      .rpc();
+
+    console.log("Your transaction signature", tx);
+
+    // --- Assert ---
+    const accountData = await program.account.identityAccount.fetch(identityPda);
+    assert.strictEqual(accountData.uri, newUri, "URI mismatch");
+    assert.deepEqual(accountData.metadataHash, newMetadataHash, "Metadata hash not updated with the URI");
+    assert.strictEqual(accountData.displayName, newDisplayName, "Display name mismatch");
+    assert.strictEqual(accountData.username, testUsername, "Username should not change");
+
+    // The NFT metadata is updated in the same instruction
+    const [metadataPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), identityMint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    const metadataInfo = await provider.connection.getAccountInfo(metadataPda);
+    assert.ok(metadataInfo.data.includes(Buffer.from(newUri)), "Metadata URI not updated");
+    assert.ok(metadataInfo.data.includes(Buffer.from(newDisplayName)), "Metadata name not updated");
+
+    // The display name is claimed, so nobody can register "sol_user"
+    const displayNameClaim = await program.account.usernameClaim.fetch(displayNameClaimPda(newDisplayName));
+    assert.ok(displayNameClaim.identity.equals(identityPda), "Display name not claimed");
+    assert.strictEqual(displayNameClaim.username, "sol_user", "Display name claim key mismatch");
+
+    console.log("✅ Identity updated successfully!");
+  });
+
+  it("16. Closes an identity and reclaims rent!", async () => {
+    // --- Arrange ---
+    // Register a fresh identity with a reputation account for a new user
+    const closingUser = anchor.web3.Keypair.generate();
+    await airdrop(closingUser.publicKey);
+    const closingUsername = "closing_agent";
+    const mintKeypair = anchor.web3.Keypair.generate();
+
+    await program.methods
+      .registerIdentity(closingUsername, testUri, testMetadataHash, { human: {} }, null)
+      .accounts({
+        authority: closingUser.publicKey,
+        payer: closingUser.publicKey,
+        identityAccount: identityPdaFor(closingUser.publicKey),
+        ownedIdentity: await nextOwnedIdentityPda(closingUser.publicKey),
+        mint: mintKeypair.publicKey,
+        usernameClaim: usernameClaimPda(closingUsername),
+        collectionMint: collectionMint,
+      })
+      .signers([closingUser, mintKeypair])
+      .rpc();
+
+    const closingIdentityPda = identityPdaFor(closingUser.publicKey);
+    const closingReputationPda = reputationPdaFor(closingIdentityPda);
+
+    await program.methods
+      .initializeReputation()
+      .accounts({
+        authority: closingUser.publicKey,
+        payer: closingUser.publicKey,
+        identityAccount: closingIdentityPda,
+        reputationAccount: closingReputationPda,
+      })
+      .signers([closingUser])
+      .rpc();
+
+    const balancePre = await provider.connection.getBalance(closingUser.publicKey);
+
+    // --- Act ---
+    const tx = await program.methods
+      .closeIdentity()
+      .accounts({
+        authority: closingUser.publicKey,
+        identityAccount: closingIdentityPda,
+        usernameClaim: usernameClaimPda(closingUsername),
+        reputationAccount: closingReputationPda, // Also close the reputation account
+        mint: mintKeypair.publicKey,
+      } as any)
+      .signers([closingUser])
+      .rpc();
+
+    console.log("Your transaction signature", tx);
+
+    // --- Assert ---
+    assert.isNull(await provider.connection.getAccountInfo(closingIdentityPda), "Identity not closed");
+    assert.isNull(await provider.connection.getAccountInfo(closingReputationPda), "Reputation not closed");
+    assert.isNull(
+      await provider.connection.getAccountInfo(usernameClaimPda(closingUsername)),
+      "Username claim not released"
+    );
+
+    const [usernameTombstonePda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("username_tombstone"), usernameClaimPda(closingUsername).toBuffer()],
+      program.programId
+    );
+    const usernameTombstoneData = await program.account.tombstone.fetch(usernameTombstonePda);
+    assert.strictEqual(usernameTombstoneData.username, closingUsername, "Username tombstone mismatch");
+
+    const [tombstonePda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("tombstone"), closingUser.publicKey.toBuffer()],
+      program.programId
+    );
+    const tombstoneData = await program.account.tombstone.fetch(tombstonePda);
+    assert.ok(tombstoneData.authority.equals(closingUser.publicKey), "Tombstone authority mismatch");
+    assert.strictEqual(tombstoneData.username, closingUsername, "Tombstone username mismatch");
+
+    const balancePost = await provider.connection.getBalance(closingUser.publicKey);
+    assert.isAbove(balancePost, balancePre, "Rent was not returned to the authority");
+
+    const registerAs = async (user: anchor.web3.Keypair, username: string) => {
+      const mintKeypair = anchor.web3.Keypair.generate();
+      return program.methods
+        .registerIdentity(username, testUri, testMetadataHash, { human: {} }, null)
+        .accounts({
+          authority: user.publicKey,
+          payer: user.publicKey,
+          identityAccount: identityPdaFor(user.publicKey, 1), // Its next free index
+          ownedIdentity: await nextOwnedIdentityPda(user.publicKey),
+          mint: mintKeypair.publicKey,
+          usernameClaim: usernameClaimPda(username),
+          collectionMint: collectionMint,
+        })
+        .signers([user, mintKeypair])
+        .rpc();
+    };
+
+    // --- The wallet cannot register again, under any username ---
+    for (const username of [closingUsername, "closing_agent_2"]) {
+      try {
+        await registerAs(closingUser, username);
+        assert.fail("Transaction should have failed (wallet tombstoned)!");
+      } catch (err: any) {
+        assert.equal(
+          err.error?.errorCode?.code,
+          "IdentityClosed",
+          `Expected program error 'IdentityClosed', got: ${JSON.stringify(err.error)}`
+        );
+      }
+    }
+
+    // --- Nobody else can take the username either ---
+    const otherUser = anchor.web3.Keypair.generate();
+    await airdrop(otherUser.publicKey);
+    try {
+      const mintKeypair2 = anchor.web3.Keypair.generate();
+      await program.methods
+        .registerIdentity(closingUsername, testUri, testMetadataHash, { human: {} }, null)
+        .accounts({
+          authority: otherUser.publicKey,
+          payer: otherUser.publicKey,
+          identityAccount: identityPdaFor(otherUser.publicKey),
+          ownedIdentity: await nextOwnedIdentityPda(otherUser.publicKey),
+          mint: mintKeypair2.publicKey,
+          usernameClaim: usernameClaimPda(closingUsername),
+          collectionMint: collectionMint,
+        })
+        .signers([otherUser, mintKeypair2])
+        .rpc();
+      assert.fail("Transaction should have failed (username retired)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "UsernameRetired",
+        `Expected program error 'UsernameRetired', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    console.log("✅ Identity closed and both the wallet and its username retired!");
+  });
+
+  it("17. Identity NFTs cannot be transferred!", async () => {
+    // --- Arrange ---
+    const identityTokenAccount = getAssociatedTokenAddressSync(identityMint, authority);
+    const tokenAccountData = await getAccount(provider.connection, identityTokenAccount);
+    assert.strictEqual(tokenAccountData.amount.toString(), "1", "Identity NFT not held");
+    assert.ok(tokenAccountData.isFrozen, "Identity token account is not frozen");
+
+    const recipient = anchor.web3.Keypair.generate();
+    const recipientTokenAccount = (await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      authorityKeypair,
+      identityMint,
+      recipient.publicKey
+    )).address;
+
+    // --- Act & Assert ---
+    try {
+      await transfer(
+        provider.connection,
+        authorityKeypair,
+        identityTokenAccount,
+        recipientTokenAccount,
+        authorityKeypair, // The owner tries to move its own identity NFT
+        1
+      );
+
+      assert.fail("Transfer should have failed (account frozen)!");
+
+    } catch (err: any) {
+      // SPL Token error 0x11: Account is frozen
+      assert.include(
+        (err.logs ?? []).join("\n") + err.message,
+        "frozen",
+        `Expected 'Account is frozen' error, got: ${err.message}`
+      );
+    }
+
+    const recipientData = await getAccount(provider.connection, recipientTokenAccount);
+    assert.strictEqual(recipientData.amount.toString(), "0", "Identity NFT was transferred");
+
+    console.log("✅ Identity NFT is soulbound");
+  });
+
+  it("18. Identity NFT authorities are controlled by the registry!", async () => {
+    const [registryAuthorityPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("registry_authority")],
+      program.programId
+    );
+    const [masterEditionPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("metadata"),
+        TOKEN_METADATA_PROGRAM_ID.toBuffer(),
+        identityMint.toBuffer(),
+        Buffer.from("edition")
+      ],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    const [metadataPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), identityMint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+
+    // Metaplex hands the mint and freeze authorities from the registry PDA to the master edition
+    const mintData = await getMint(provider.connection, identityMint);
+    assert.ok(mintData.mintAuthority.equals(masterEditionPda), "Mint authority is not the master edition");
+    assert.ok(mintData.freezeAuthority.equals(masterEditionPda), "Freeze authority is not the master edition");
+    assert.strictEqual(mintData.supply.toString(), "1", "Supply is not 1");
+
+    // Metadata layout: 1 (key) + 32 (update authority) + 32 (mint) + ...
+    const metadataInfo = await provider.connection.getAccountInfo(metadataPda);
+    const updateAuthority = new anchor.web3.PublicKey(metadataInfo.data.subarray(1, 33));
+    assert.ok(updateAuthority.equals(registryAuthorityPda), "Update authority is not the registry PDA");
+
+    console.log("✅ Identity NFT authorities are held by the registry");
+  });
+
+  it("19. Identity NFTs are verified members of the registry collection!", async () => {
+    const [metadataPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), identityMint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    const metadataInfo = await provider.connection.getAccountInfo(metadataPda);
+
+    // Borsh layout of the metadata's `collection` field: Some (1) + verified (1) + key (32)
+    const verifiedCollection = Buffer.concat([Buffer.from([1, 1]), collectionMint.toBuffer()]);
+    assert.ok(metadataInfo.data.includes(verifiedCollection), "Identity NFT is not a verified collection member");
+
+    const [configPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const configData = await program.account.registryConfig.fetch(configPda);
+    assert.ok(configData.admin.equals(authority), "Admin mismatch");
+    assert.ok(configData.collectionMint.equals(collectionMint), "Collection mint mismatch");
+
+    console.log("✅ Identity NFT is a verified collection member");
+  });
+
+  it("20. Verifies that a signer holds the identity NFT!", async () => {
+    const identityTokenAccount = getAssociatedTokenAddressSync(identityMint, authority);
+
+    // The NFT holder passes
+    await program.methods
+      .verifyIdentity()
+      .accounts({
+        identityHolder: {
+          holder: authority,
+          identityAccount: identityPda,
+          identityTokenAccount: identityTokenAccount,
+        },
+      } as any)
+      .rpc();
+
+    // Anyone else fails, even when pointing at the holder's token account
+    try {
+      await program.methods
+        .verifyIdentity()
+        .accounts({
+          identityHolder: {
+            holder: payerUser.publicKey,
+            identityAccount: identityPda,
+            identityTokenAccount: identityTokenAccount,
+          },
+        } as any)
+        .signers([payerUser])
+        .rpc();
+
+      assert.fail("Transaction should have failed (signer does not hold the NFT)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "ConstraintTokenOwner",
+        `Expected 'ConstraintTokenOwner' error, got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    console.log("✅ Identity NFT ownership verified");
+  });
+
+  it("21. Authority changes are timelocked and can be cancelled!", async () => {
+    // --- Arrange ---
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    await airdrop(oldKey.publicKey);
+    const agent = await registerAgent(oldKey, "timelocked_agent");
+
+    await program.methods
+      .setAuthorityChangeDelay(new anchor.BN(3600))
+      .accounts({ admin: authority } as any)
+      .rpc();
+
+    await program.methods
+      .proposeAuthorityChange(newKey.publicKey)
+      .accounts({ authority: oldKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([oldKey])
+      .rpc();
+
+    // --- Act & Assert ---
+    try {
+      await program.methods
+        .acceptAuthorityChange(null)
+        .accounts({
+          newAuthority: newKey.publicKey,
+          oldAuthority: oldKey.publicKey,
+          identityAccount: agent.identityPda,
+          newOwnedIdentity: await nextOwnedIdentityPda(newKey.publicKey),
+          reputationAccount: agent.reputationPda,
+          usernameClaim: usernameClaimPda("timelocked_agent"),
+          mint: agent.mint,
+        } as any)
+        .signers([newKey])
+        .rpc();
+
+      assert.fail("Transaction should have failed (timelocked)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "AuthorityChangeTimelocked",
+        `Expected program error 'AuthorityChangeTimelocked', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    await program.methods
+      .cancelAuthorityChange()
+      .accounts({ authority: oldKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([oldKey])
+      .rpc();
+
+    const [pendingPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("authority_change"), agent.identityPda.toBuffer()],
+      program.programId
+    );
+    assert.isNull(await provider.connection.getAccountInfo(pendingPda), "Pending change not cancelled");
+
+    console.log("✅ Authority change timelocked and cancelled");
+  });
+
+  it("22. Moves an identity, reputation and NFT to a new authority!", async () => {
+    // --- Arrange ---
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    await airdrop(oldKey.publicKey);
+    await airdrop(newKey.publicKey);
+    const rotatingUsername = "rotating_agent";
+    const agent = await registerAgent(oldKey, rotatingUsername);
+
+    await program.methods
+      .setAuthorityChangeDelay(new anchor.BN(0))
+      .accounts({ admin: authority } as any)
+      .rpc();
+
+    await program.methods
+      .proposeAuthorityChange(newKey.publicKey)
+      .accounts({ authority: oldKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([oldKey])
+      .rpc();
+
+    // --- Act ---
+    const tx = await program.methods
+      .acceptAuthorityChange(null)
+      .accounts({
+        newAuthority: newKey.publicKey,
+        oldAuthority: oldKey.publicKey,
+        identityAccount: agent.identityPda,
+        newOwnedIdentity: await nextOwnedIdentityPda(newKey.publicKey),
+        reputationAccount: agent.reputationPda,
+        usernameClaim: usernameClaimPda(rotatingUsername),
+        mint: agent.mint,
+      } as any)
+      .signers([newKey])
+      .rpc();
+
+    console.log("Your transaction signature", tx);
+
+    // --- Assert ---
+    // The identity and reputation keep their addresses, so everything keyed
+    // by the identity PDA stays attached to it
+    const identityData = await program.account.identityAccount.fetch(agent.identityPda);
+    assert.ok(identityData.authority.equals(newKey.publicKey), "Identity authority not moved");
+    assert.ok(identityData.creator.equals(oldKey.publicKey), "Identity creator changed");
+    assert.ok(identityData.mint.equals(agent.mint), "Identity mint changed");
+    assert.strictEqual(identityData.username, rotatingUsername, "Username changed");
+
+    const reputationData = await program.account.reputationAccount.fetch(agent.reputationPda);
+    assert.ok(reputationData.authority.equals(newKey.publicKey), "Reputation authority not moved");
+
+    const claimData = await program.account.usernameClaim.fetch(usernameClaimPda(rotatingUsername));
+    assert.ok(claimData.authority.equals(newKey.publicKey), "Username claim not moved");
+    assert.ok(claimData.identity.equals(agent.identityPda), "Username claim identity changed");
+
+    // The NFT is in the new key's token account, still frozen
+    const newTokenAccount = await getAccount(
+      provider.connection,
+      getAssociatedTokenAddressSync(agent.mint, newKey.publicKey)
+    );
+    assert.strictEqual(newTokenAccount.amount.toString(), "1", "NFT not moved");
+    assert.ok(newTokenAccount.isFrozen, "NFT not frozen after move");
+    const [registryAuthorityPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("registry_authority")],
+      program.programId
+    );
+    assert.ok(
+      newTokenAccount.closeAuthority?.equals(registryAuthorityPda),
+      "Registry PDA is not the new token account's close authority"
+    );
+
+    // The old key's emptied token account is closed and its rent refunded
+    assert.isNull(
+      await provider.connection.getAccountInfo(getAssociatedTokenAddressSync(agent.mint, oldKey.publicKey)),
+      "Old token account not closed"
+    );
+
+    // The new key controls the identity and the old key no longer does
+    await program.methods
+      .setSuspended(true)
+      .accounts({ authority: newKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([newKey])
+      .rpc();
+    assert.isTrue(
+      (await program.account.identityAccount.fetch(agent.identityPda)).suspended,
+      "New authority can't manage the identity"
+    );
+
+    try {
+      await program.methods
+        .setSuspended(false)
+        .accounts({ authority: oldKey.publicKey, identityAccount: agent.identityPda } as any)
+        .signers([oldKey])
+        .rpc();
+
+      assert.fail("Transaction should have failed (old authority)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "ConstraintHasOne",
+        `Expected error 'ConstraintHasOne', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    // The old key can't register a fresh identity
+    try {
+      await registerAgent(oldKey, "rotating_agent_2", { human: {} }, 1);
+      assert.fail("Transaction should have failed (old key tombstoned)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "IdentityClosed",
+        `Expected program error 'IdentityClosed', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    console.log("✅ Authority changed successfully!");
+  });
+
+  it("23. Operators can act within their permissions!", async () => {
+    // --- Arrange ---
+    const operator = anchor.web3.Keypair.generate();
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+    const OPERATOR_UPDATE_METADATA = 1 << 0;
+
+    await program.methods
+      .addOperator(operator.publicKey, OPERATOR_UPDATE_METADATA, expiresAt)
+      .accounts({ authority: authority, identityAccount: identityPda } as any)
+      .rpc();
+
+    const identityData = await program.account.identityAccount.fetch(identityPda);
+    assert.strictEqual(identityData.operators.length, 1, "Operator not added");
+    assert.ok(identityData.operators[0].key.equals(operator.publicKey), "Operator key mismatch");
+
+    // --- Act ---
+    const operatorUri = "https://arweave.net/operator-profile-json";
+    await program.methods
+      .updateIdentity(operatorUri, testMetadataHash, null)
+      .accounts({
+        signer: operator.publicKey,
+        identityAccount: identityPda,
+        displayNameClaim: displayNameClaimPda("Sol User"), // Set in test 15
+        metadataVersion: metadataVersionPdaFor(identityPda, 1),
+        mint: identityMint,
+      } as any)
+      .signers([operator])
+      .rpc();
+
+    // --- Assert ---
+    const updatedData = await program.account.identityAccount.fetch(identityPda);
+    assert.strictEqual(updatedData.uri, operatorUri, "Operator could not update the URI");
+
+    // A key that isn't an operator is rejected
+    try {
+      await program.methods
+        .updateIdentity(testUri, testMetadataHash, null)
+        .accounts({
+          signer: payerUser.publicKey,
+          identityAccount: identityPda,
+          displayNameClaim: displayNameClaimPda("Sol User"),
+          metadataVersion: metadataVersionPdaFor(identityPda, 2),
+          mint: identityMint,
+        } as any)
+        .signers([payerUser])
+        .rpc();
+
+      assert.fail("Transaction should have failed (not an operator)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "OperatorNotAuthorized",
+        `Expected program error 'OperatorNotAuthorized', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    console.log("✅ Operator acted within its permissions");
+  });
+
+  it("24. The kill switch suspends operators and payments, and operators can be revoked!", async () => {
+    // --- Arrange ---
+    const identityData = await program.account.identityAccount.fetch(identityPda);
+    const operatorKey = identityData.operators[0].key;
+
+    await program.methods
+      .setSuspended(true)
+      .accounts({ authority: authority, identityAccount: identityPda } as any)
+      .rpc();
+
+    // --- Act & Assert ---
+    // Payments to a suspended agent are rejected
+    try {
+      await program.methods
+        .logServiceTransaction(transactionAmount)
+        .accounts({
+          payer: payerUser.publicKey,
+          authority: authority,
+          identityAccount: identityPda,
+          mint: mockUsdcMint,
+          paymentPolicy: paymentPolicyPdaFor(identityPda, mockUsdcMint),
+        } as any)
+        .signers([payerUser])
+        .rpc();
+
+      assert.fail("Transaction should have failed (identity suspended)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "IdentitySuspended",
+        `Expected program error 'IdentitySuspended', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    await program.methods
+      .setSuspended(false)
+      .accounts({ authority: authority, identityAccount: identityPda } as any)
+      .rpc();
+
+    await program.methods
+      .revokeOperator(operatorKey)
+      .accounts({ authority: authority, identityAccount: identityPda } as any)
+      .rpc();
+
+    const revokedData = await program.account.identityAccount.fetch(identityPda);
+    assert.isFalse(revokedData.suspended, "Identity still suspended");
+    assert.strictEqual(revokedData.operators.length, 0, "Operator not revoked");
+
+    console.log("✅ Kill switch and operator revocation work");
+  });
+
+  it("25. A sponsor pays for a new agent with no SOL!", async () => {
+    // --- Arrange ---
+    // The agent's wallet is never funded
+    const sponsoredUser = anchor.web3.Keypair.generate();
+    const sponsoredUsername = "sponsored_agent";
+    const mintKeypair = anchor.web3.Keypair.generate();
+
+    // --- Act ---
+    // The provider wallet sponsors rent and fees; the agent only signs
+    await program.methods
+      .registerIdentity(sponsoredUsername, testUri, testMetadataHash, { human: {} }, null)
+      .accounts({
+        authority: sponsoredUser.publicKey,
+        payer: authority,
+        identityAccount: identityPdaFor(sponsoredUser.publicKey),
+        ownedIdentity: await nextOwnedIdentityPda(sponsoredUser.publicKey),
+        mint: mintKeypair.publicKey,
+        usernameClaim: usernameClaimPda(sponsoredUsername),
+        collectionMint: collectionMint,
+      })
+      .signers([sponsoredUser, mintKeypair])
+      .rpc();
+
+    const sponsoredIdentityPda = identityPdaFor(sponsoredUser.publicKey);
+    const sponsoredReputationPda = reputationPdaFor(sponsoredIdentityPda);
+
+    await program.methods
+      .initializeReputation()
+      .accounts({
+        authority: sponsoredUser.publicKey,
+        payer: authority,
+        identityAccount: sponsoredIdentityPda,
+        reputationAccount: sponsoredReputationPda,
+      })
+      .signers([sponsoredUser])
+      .rpc();
+
+    // --- Assert ---
+    const identityData = await program.account.identityAccount.fetch(sponsoredIdentityPda);
+    assert.ok(identityData.authority.equals(sponsoredUser.publicKey), "Authority mismatch");
+    await program.account.reputationAccount.fetch(sponsoredReputationPda);
+
+    const balance = await provider.connection.getBalance(sponsoredUser.publicKey);
+    assert.strictEqual(balance, 0, "The agent paid for something");
+
+    console.log("✅ Sponsored registration succeeded");
+  });
+
+  it("26. A wallet can own several identities, each with its own reputation!", async () => {
+    // --- Arrange ---
+    // The authority already owns identity 0 from test 1
+    const [counterPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("identity_counter"), authority.toBuffer()],
+      program.programId
+    );
+    const counterPre = await program.account.identityCounter.fetch(counterPda);
+    assert.strictEqual(counterPre.count, 1, "Authority should own one identity");
+
+    const fleetUsername = "sol_user_fleet_1";
+    const fleetIdentityPda = identityPdaFor(authority, 1);
+    const fleetReputationPda = reputationPdaFor(fleetIdentityPda);
+    const mintKeypair = anchor.web3.Keypair.generate();
+
+    // --- Act ---
+    await program.methods
+      .registerIdentity(fleetUsername, testUri, testMetadataHash, { human: {} }, null)
+      .accounts({
+        authority: authority,
+        payer: authority,
+        identityAccount: fleetIdentityPda,
+        ownedIdentity: await nextOwnedIdentityPda(authority),
+        mint: mintKeypair.publicKey,
+        usernameClaim: usernameClaimPda(fleetUsername),
+        collectionMint: collectionMint,
+      })
+      .signers([mintKeypair])
+      .rpc();
+
+    await program.methods
+      .initializeReputation()
+      .accounts({
+        authority: authority,
+        payer: authority,
+        identityAccount: fleetIdentityPda,
+        reputationAccount: fleetReputationPda,
+      })
+      .rpc();
+
+    // Payment rules are per identity, too
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(1), new anchor.BN(1000 * 1_000_000))
+      .accounts({
+        signer: authority,
+        payer: authority,
+        identityAccount: fleetIdentityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: paymentPolicyPdaFor(fleetIdentityPda, mockUsdcMint),
+      } as any)
+      .rpc();
+
+    await program.methods
+      .logServiceTransaction(transactionAmount)
+      .accounts({
+        payer: payerUser.publicKey,
+        authority: authority,
+        identityAccount: fleetIdentityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: paymentPolicyPdaFor(fleetIdentityPda, mockUsdcMint),
+      } as any)
+      .signers([payerUser])
+      .rpc();
+
+    // --- Assert ---
+    const counterPost = await program.account.identityCounter.fetch(counterPda);
+    assert.strictEqual(counterPost.count, 2, "Identity counter did not increment");
+
+    // Clients enumerate the identities the wallet owns now from the owned list
+    assert.strictEqual(counterPost.ownedCount, 2, "Owned count did not increment");
+    const owned = await Promise.all(
+      [0, 1].map((slot) => program.account.ownedIdentity.fetch(ownedIdentityPdaFor(authority, slot)))
+    );
+    assert.deepEqual(
+      owned.map((entry) => entry.identity.toBase58()),
+      [identityPda.toBase58(), fleetIdentityPda.toBase58()],
+      "Owned list mismatch"
+    );
+
+    const fleetIdentity = await program.account.identityAccount.fetch(fleetIdentityPda);
+    assert.strictEqual(fleetIdentity.index, 1, "Index mismatch");
+    assert.strictEqual(fleetIdentity.username, fleetUsername, "Username mismatch");
+    assert.ok(fleetIdentity.mint.equals(mintKeypair.publicKey), "Each identity has its own NFT");
+
+    // The payment only counts towards the second identity's reputation
+    const fleetReputation = await program.account.reputationAccount.fetch(fleetReputationPda);
+    assert.strictEqual(fleetReputation.totalTransactions.toNumber(), 1, "Payment not recorded");
+    const firstReputation = await program.account.reputationAccount.fetch(reputationPda);
+    assert.strictEqual(firstReputation.totalTransactions.toNumber(), 1, "First identity's reputation changed");
+
+    // Clients enumerate a wallet's identities from the counter
+    for (let index = 0; index < counterPost.count; index++) {
+      const identity = await program.account.identityAccount.fetch(identityPdaFor(authority, index));
+      assert.strictEqual(identity.index, index, "Enumerated index mismatch");
+    }
+
+    console.log("✅ Wallet owns two independent identities");
+  });
+
+  it("27. Organizations admit members by mutual signature and aggregate their reputation!", async () => {
+    // --- Arrange ---
+    const orgName = "acme_agents";
+    const [orgPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("organization"), createHash("sha256").update(orgName).digest()],
+      program.programId
+    );
+    const membershipPda = (identity: anchor.web3.PublicKey) =>
+      anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("org_member"), orgPda.toBuffer(), identity.toBuffer()],
+        program.programId
+      )[0];
+
+    // The organization is run from its own organization identity
+    const orgAdmin = anchor.web3.Keypair.generate();
+    await airdrop(orgAdmin.publicKey);
+    const orgIdentity = await registerAgent(orgAdmin, "acme_org", { organization: {} });
+
+    // --- Act ---
+    // The organization identity creates the organization and becomes its admin
+    await program.methods
+      .createOrganization(orgName, "https://arweave.net/acme-json")
+      .accounts({
+        authority: orgAdmin.publicKey,
+        identityAccount: orgIdentity.identityPda,
+        organization: orgPda,
+        membership: membershipPda(orgIdentity.identityPda),
+      } as any)
+      .signers([orgAdmin])
+      .rpc();
+
+    // The authority's first identity joins. The member and an admin both sign.
+    await program.methods
+      .joinOrganization({ member: {} })
+      .accounts({
+        authority: authority,
+        identityAccount: identityPda,
+        admin: orgAdmin.publicKey,
+        adminIdentity: orgIdentity.identityPda,
+        adminMembership: membershipPda(orgIdentity.identityPda),
+        organization: orgPda,
+        membership: membershipPda(identityPda),
+      } as any)
+      .signers([orgAdmin])
+      .rpc();
+
+    // Anyone can sync a member's reputation into the organization
+    await program.methods
+      .syncMemberReputation()
+      .accounts({
+        organization: orgPda,
+        membership: membershipPda(identityPda),
+        reputationAccount: reputationPda,
+      } as any)
+      .rpc();
+
+    // --- Assert ---
+    const orgData = await program.account.organizationAccount.fetch(orgPda);
+    const memberReputation = await program.account.reputationAccount.fetch(reputationPda);
+    assert.strictEqual(orgData.memberCount, 2, "Member count mismatch");
+    assert.strictEqual(orgData.adminCount, 1, "Admin count mismatch");
+    assert.ok(orgData.totalTransactions.eq(memberReputation.totalTransactions), "Transactions not aggregated");
+    assert.ok(orgData.totalVolume.eq(memberReputation.totalVolume), "Volume not aggregated");
+    assert.ok(orgData.totalRatingScore.eq(memberReputation.totalRatingScore), "Rating not aggregated");
+
+    // The member can prove its membership by holding its identity NFT
+    await program.methods
+      .verifyMembership()
+      .accounts({
+        identityHolder: {
+          holder: authority,
+          identityAccount: identityPda,
+          identityTokenAccount: getAssociatedTokenAddressSync(identityMint, authority),
+        },
+        organization: orgPda,
+        membership: membershipPda(identityPda),
+      } as any)
+      .rpc();
+
+    // A member who isn't an admin can't remove anyone
+    try {
+      await program.methods
+        .removeMember()
+        .accounts({
+          admin: authority,
+          adminIdentity: identityPda,
+          adminMembership: membershipPda(identityPda),
+          organization: orgPda,
+          membership: membershipPda(orgIdentity.identityPda),
+          memberIdentity: orgIdentity.identityPda,
+          memberAuthority: orgAdmin.publicKey,
+        } as any)
+        .rpc();
+
+      assert.fail("Transaction should have failed (not an admin)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "NotOrganizationAdmin",
+        `Expected program error 'NotOrganizationAdmin', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    // The admin removes the member, and its reputation leaves the aggregate
+    await program.methods
+      .removeMember()
+      .accounts({
+        admin: orgAdmin.publicKey,
+        adminIdentity: orgIdentity.identityPda,
+        adminMembership: membershipPda(orgIdentity.identityPda),
+        organization: orgPda,
+        membership: membershipPda(identityPda),
+        memberIdentity: identityPda,
+        memberAuthority: authority,
+      } as any)
+      .signers([orgAdmin])
+      .rpc();
+
+    assert.isNull(await provider.connection.getAccountInfo(membershipPda(identityPda)), "Membership not closed");
+    const orgAfter = await program.account.organizationAccount.fetch(orgPda);
+    assert.strictEqual(orgAfter.memberCount, 1, "Member count not decremented");
+    assert.strictEqual(
+      (await program.account.identityAccount.fetch(identityPda)).orgMembershipCount,
+      0,
+      "Identity membership count not decremented"
+    );
+    assert.strictEqual(orgAfter.totalTransactions.toNumber(), 0, "Member reputation not removed");
+
+    // Only organization identities can create organizations
+    const [otherOrgPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("organization"), createHash("sha256").update("acme_agents_2").digest()],
+      program.programId
+    );
+    try {
+      await program.methods
+        .createOrganization("acme_agents_2", "https://arweave.net/acme-json")
+        .accounts({
+          authority: authority,
+          identityAccount: identityPda,
+          organization: otherOrgPda,
+        } as any)
+        .rpc();
+
+      assert.fail("Transaction should have failed (not an organization identity)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "InvalidIdentityKind",
+        `Expected program error 'InvalidIdentityKind', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    console.log("✅ Organization membership and reputation aggregation work");
+  });
+
+  it("28. Identities vouch for sub-identities and can revoke them!", async () => {
+    // --- Arrange ---
+    // The sub-agent has its own key. The authority's first identity vouches for it and pays.
+    const childKey = anchor.web3.Keypair.generate();
+    const childUsername = "sol_user_sub_agent";
+    const childIdentityPda = identityPdaFor(childKey.publicKey);
+    const childMint = anchor.web3.Keypair.generate();
+
+    // --- Act ---
+    await program.methods
+      .registerSubIdentity(childUsername, testUri, testMetadataHash, { human: {} }, null)
+      .accounts({
+        register: {
+          authority: childKey.publicKey,
+          payer: authority,
+          identityAccount: childIdentityPda,
+          ownedIdentity: await nextOwnedIdentityPda(childKey.publicKey),
+          mint: childMint.publicKey,
+          usernameClaim: usernameClaimPda(childUsername),
+          collectionMint: collectionMint,
+        },
+        parentSigner: authority,
+        parentIdentity: identityPda,
+      } as any)
+      .signers([childKey, childMint])
+      .rpc();
+
+    // --- Assert ---
+    // Clients walk up the chain until they reach an identity without a parent
+    const childData = await program.account.identityAccount.fetch(childIdentityPda);
+    assert.ok(childData.parent.equals(identityPda), "Parent link not recorded");
+    assert.strictEqual(childData.depth, 1, "Depth mismatch");
+    const rootData = await program.account.identityAccount.fetch(childData.parent);
+    assert.ok(rootData.parent.equals(anchor.web3.PublicKey.default), "Root should have no parent");
+    assert.strictEqual(rootData.depth, 0, "Root depth mismatch");
+
+    // The parent revokes the child
+    await program.methods
+      .revokeSubIdentity()
+      .accounts({
+        parentSigner: authority,
+        parentIdentity: identityPda,
+        childIdentity: childIdentityPda,
+      } as any)
+      .rpc();
+
+    const revokedData = await program.account.identityAccount.fetch(childIdentityPda);
+    assert.isTrue(revokedData.revoked, "Child not revoked");
+
+    // A revoked identity can't vouch for sub-identities of its own
+    const grandchildKey = anchor.web3.Keypair.generate();
+    const grandchildMint = anchor.web3.Keypair.generate();
+    try {
+      await program.methods
+        .registerSubIdentity("sol_user_sub_sub_agent", testUri, testMetadataHash, { human: {} }, null)
+        .accounts({
+          register: {
+            authority: grandchildKey.publicKey,
+            payer: authority,
+            identityAccount: identityPdaFor(grandchildKey.publicKey),
+            ownedIdentity: await nextOwnedIdentityPda(grandchildKey.publicKey),
+            mint: grandchildMint.publicKey,
+            usernameClaim: usernameClaimPda("sol_user_sub_sub_agent"),
+            collectionMint: collectionMint,
+          },
+          parentSigner: childKey.publicKey,
+          parentIdentity: childIdentityPda,
+        } as any)
+        .signers([grandchildKey, grandchildMint, childKey])
+        .rpc();
+
+      assert.fail("Transaction should have failed (parent revoked)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "IdentityRevoked",
+        `Expected program error 'IdentityRevoked', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    console.log("✅ Sub-identity vouched for and revoked");
+  });
+
+  it("29. Identity kinds are enforced and filterable!", async () => {
+    // --- Arrange ---
+    const agentKey = anchor.web3.Keypair.generate();
+    const runtimeKey = anchor.web3.Keypair.generate();
+    await airdrop(agentKey.publicKey);
+    const agentUsername = "kind_ai_agent";
+    const agentIdentityPda = identityPdaFor(agentKey.publicKey);
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+    const OPERATOR_UPDATE_METADATA = 1 << 0;
+
+    const register = async (kind: any, operator: any, username = agentUsername) => {
+      const mintKeypair = anchor.web3.Keypair.generate();
+      return program.methods
+        .registerIdentity(username, testUri, testMetadataHash, kind, operator)
+        .accounts({
+          authority: agentKey.publicKey,
+          payer: agentKey.publicKey,
+          identityAccount: agentIdentityPda,
+          ownedIdentity: await nextOwnedIdentityPda(agentKey.publicKey),
+          mint: mintKeypair.publicKey,
+          usernameClaim: usernameClaimPda(username),
+          collectionMint: collectionMint,
+        })
+        .signers([agentKey, mintKeypair])
+        .rpc();
+    };
+
+    const expectError = async (promise: Promise<any>, code: string) => {
+      try {
+        await promise;
+        assert.fail(`Transaction should have failed (${code})!`);
+      } catch (err: any) {
+        assert.equal(
+          err.error?.errorCode?.code,
+          code,
+          `Expected program error '${code}', got: ${JSON.stringify(err.error)}`
+        );
+      }
+    };
+
+    // --- Act & Assert ---
+    // AI agents must declare an operator
+    await expectError(register({ aiAgent: {} }, null), "OperatorRequired");
+
+    // Program identities can only be registered by their program, through CPI
+    await expectError(register({ program: {} }, null), "ProgramIdentityRequiresCpi");
+
+    await register({ aiAgent: {} }, {
+      key: runtimeKey.publicKey,
+      permissions: OPERATOR_UPDATE_METADATA,
+      expiresAt: expiresAt,
+    });
+
+    const agentData = await program.account.identityAccount.fetch(agentIdentityPda);
+    assert.deepEqual(agentData.kind, { aiAgent: {} }, "Kind mismatch");
+    assert.ok(agentData.operators[0].key.equals(runtimeKey.publicKey), "Operator not declared");
+
+    // The AI agent can't drop its last operator
+    await expectError(
+      program.methods
+        .revokeOperator(runtimeKey.publicKey)
+        .accounts({ authority: agentKey.publicKey, identityAccount: agentIdentityPda } as any)
+        .signers([agentKey])
+        .rpc(),
+      "OperatorRequired"
+    );
+
+    // Clients find every AI agent with a memcmp filter on the kind byte
+    const KIND_OFFSET = 8 + 32 + 32 + 4 + 1 + 32 + 1 + 1;
+    const aiAgents = await program.account.identityAccount.all([
+      { memcmp: { offset: KIND_OFFSET, bytes: anchor.utils.bytes.bs58.encode(Buffer.from([1])) } },
+    ]);
+    assert.ok(aiAgents.some((a) => a.publicKey.equals(agentIdentityPda)), "AI agent not found by kind");
+    assert.ok(aiAgents.every((a) => "aiAgent" in a.account.kind), "Filter returned other kinds");
+
+    // The kind is also the NFT's on-chain symbol. Metaplex pads the symbol to
+    // 10 bytes after a 4-byte length, at offset 1 + 32 + 32 + (4 + 32) + 4.
+    const [metadataPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), agentData.mint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    const metadataInfo = await provider.connection.getAccountInfo(metadataPda);
+    const SYMBOL_OFFSET = 1 + 32 + 32 + 4 + 32 + 4;
+    assert.strictEqual(
+      metadataInfo.data.subarray(SYMBOL_OFFSET, SYMBOL_OFFSET + 10).toString().replace(/\0/g, ""),
+      "AIAGENT",
+      "Kind not in the NFT symbol"
+    );
+
+    console.log("✅ Identity kinds enforced and filterable");
+  });
+
+  it("30. Identities publish service endpoints that grow and shrink on-chain!", async () => {
+    // --- Arrange ---
+    const agentKey = anchor.web3.Keypair.generate();
+    await airdrop(agentKey.publicKey);
+    const { identityPda } = await registerAgent(agentKey, "endpoint_agent");
+    const [endpointsPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("endpoints"), identityPda.toBuffer()],
+      program.programId
+    );
+    const accounts = { signer: agentKey.publicKey, identityAccount: identityPda, endpoints: endpointsPda } as any;
+    const dataLength = async () => (await provider.connection.getAccountInfo(endpointsPda))!.data.length;
+
+    // --- Act ---
+    await program.methods.initializeEndpoints().accounts(accounts).signers([agentKey]).rpc();
+    const emptyLength = await dataLength();
+
+    await program.methods
+      .addEndpoint({ x402: {} }, "https://agent.example.com/x402", "translate")
+      .accounts(accounts)
+      .signers([agentKey])
+      .rpc();
+    await program.methods
+      .addEndpoint({ mcp: {} }, "https://agent.example.com/mcp", "tools")
+      .accounts(accounts)
+      .signers([agentKey])
+      .rpc();
+    const fullLength = await dataLength();
+
+    await program.methods
+      .updateEndpoint(0, { a2a: {} }, "https://agent.example.com/a2a", "chat")
+      .accounts(accounts)
+      .signers([agentKey])
+      .rpc();
+
+    // --- Assert ---
+    let data = await program.account.serviceEndpoints.fetch(endpointsPda);
+    assert.ok(data.identity.equals(identityPda), "Identity mismatch");
+    assert.equal(data.endpoints.length, 2, "Endpoint count mismatch");
+    assert.deepEqual(data.endpoints[0].protocol, { a2a: {} }, "Protocol not updated");
+    assert.equal(data.endpoints[0].url, "https://agent.example.com/a2a", "URL not updated");
+    assert.equal(data.endpoints[0].label, "chat", "Label not updated");
+    assert.ok(fullLength > emptyLength, "Account did not grow");
+
+    // Removing an endpoint shrinks the account and keeps the order of the rest
+    await program.methods.removeEndpoint(0).accounts(accounts).signers([agentKey]).rpc();
+    data = await program.account.serviceEndpoints.fetch(endpointsPda);
+    assert.equal(data.endpoints.length, 1, "Endpoint not removed");
+    assert.equal(data.endpoints[0].label, "tools", "Wrong endpoint removed");
+    assert.ok((await dataLength()) < fullLength, "Account did not shrink");
+
+    // Out-of-range indexes are rejected
+    try {
+      await program.methods.removeEndpoint(5).accounts(accounts).signers([agentKey]).rpc();
+      assert.fail("Removing a missing endpoint should have failed!");
+    } catch (err: any) {
+      assert.equal(err.error?.errorCode?.code, "EndpointNotFound", `Unexpected error: ${JSON.stringify(err.error)}`);
+    }
+
+    // Strangers can't edit the list
+    const stranger = anchor.web3.Keypair.generate();
+    await airdrop(stranger.publicKey);
+    try {
+      await program.methods
+        .addEndpoint({ x402: {} }, "https://evil.example.com", "evil")
+        .accounts({ ...accounts, signer: stranger.publicKey })
+        .signers([stranger])
+        .rpc();
+      assert.fail("A stranger should not be able to add endpoints!");
+    } catch (err: any) {
+      assert.equal(err.error?.errorCode?.code, "OperatorNotAuthorized", `Unexpected error: ${JSON.stringify(err.error)}`);
+    }
+
+    console.log("✅ Service endpoints added, updated and removed");
+  });
+
+  it("31. Capability tags keep a dense, enumerable index!", async () => {
+    // --- Arrange ---
+    const tag = "translation";
+    const [tagIndexPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("tag"), createHash("sha256").update(tag).digest()],
+      program.programId
+    );
+    const tagEntryPda = (i: number) => {
+      const index = Buffer.alloc(4);
+      index.writeUInt32LE(i);
+      return anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("tag_entry"), tagIndexPda.toBuffer(), index],
+        program.programId
+      )[0];
+    };
+    const tagMemberPda = (identity: anchor.web3.PublicKey) =>
+      anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("tag_member"), tagIndexPda.toBuffer(), identity.toBuffer()],
+        program.programId
+      )[0];
+
+    // Reads the index the way a client would: derive every slot up to `count`
+    const listTagged = async () => {
+      const { count } = await program.account.tagIndex.fetch(tagIndexPda);
+      const entries = await Promise.all([...Array(count).keys()].map((i) => program.account.tagEntry.fetch(tagEntryPda(i))));
+      return entries.map((e) => e.identity.toBase58());
+    };
+
+    const agents: { key: anchor.web3.Keypair; identityPda: anchor.web3.PublicKey }[] = [];
+    for (const name of ["tag_agent_a", "tag_agent_b", "tag_agent_c"]) {
+      const key = anchor.web3.Keypair.generate();
+      await airdrop(key.publicKey);
+      const { identityPda } = await registerAgent(key, name);
+      agents.push({ key, identityPda });
+    }
+
+    // --- Act ---
+    for (const [i, agent] of agents.entries()) {
+      await program.methods
+        .addTag(i === 0 ? "Translation" : tag) // Tags are normalized like usernames
+        .accounts({
+          signer: agent.key.publicKey,
+          identityAccount: agent.identityPda,
+          tagIndex: tagIndexPda,
+          tagEntry: tagEntryPda(i),
+          tagMember: tagMemberPda(agent.identityPda),
+        } as any)
+        .signers([agent.key])
+        .rpc();
+    }
+
+    // --- Assert ---
+    assert.deepEqual(await listTagged(), agents.map((a) => a.identityPda.toBase58()), "Index mismatch");
+    const tagged = await program.account.identityAccount.fetch(agents[0].identityPda);
+    assert.deepEqual(tagged.tags, [tag], "Tag not stored on the identity");
+
+    // Closing a tagged identity would leave a dangling index entry
+    try {
+      await program.methods
+        .closeIdentity()
+        .accounts({
+          authority: agents[0].key.publicKey,
+          identityAccount: agents[0].identityPda,
+          usernameClaim: usernameClaimPda("tag_agent_a"),
+          mint: tagged.mint,
+        } as any)
+        .signers([agents[0].key])
+        .rpc();
+      assert.fail("Closing a tagged identity should have failed!");
+    } catch (err: any) {
+      assert.equal(err.error?.errorCode?.code, "IdentityHasTags", `Unexpected error: ${JSON.stringify(err.error)}`);
+    }
+
+    // Removing the first agent moves the last one into its slot
+    await program.methods
+      .removeTag(tag)
+      .accounts({
+        signer: agents[0].key.publicKey,
+        identityAccount: agents[0].identityPda,
+        tagIndex: tagIndexPda,
+        tagMember: tagMemberPda(agents[0].identityPda),
+        lastEntry: tagEntryPda(2),
+        entry: tagEntryPda(0),
+        lastMember: tagMemberPda(agents[2].identityPda),
+      } as any)
+      .signers([agents[0].key])
+      .rpc();
+
+    assert.deepEqual(
+      await listTagged(),
+      [agents[2].identityPda.toBase58(), agents[1].identityPda.toBase58()],
+      "Index not compacted"
+    );
+    const moved = await program.account.tagMember.fetch(tagMemberPda(agents[2].identityPda));
+    assert.equal(moved.index, 0, "Moved membership not updated");
+    assert.isNull(await provider.connection.getAccountInfo(tagEntryPda(2)), "Last slot not closed");
+    assert.isNull(await provider.connection.getAccountInfo(tagMemberPda(agents[0].identityPda)), "Membership not closed");
+
+    // Removing the last entry needs no moved accounts
+    await program.methods
+      .removeTag(tag)
+      .accounts({
+        signer: agents[1].key.publicKey,
+        identityAccount: agents[1].identityPda,
+        tagIndex: tagIndexPda,
+        tagMember: tagMemberPda(agents[1].identityPda),
+        lastEntry: tagEntryPda(1),
+        entry: null,
+        lastMember: null,
+      } as any)
+      .signers([agents[1].key])
+      .rpc();
+
+    assert.deepEqual(await listTagged(), [agents[2].identityPda.toBase58()], "Last entry not removed");
+    const untagged = await program.account.identityAccount.fetch(agents[1].identityPda);
+    assert.deepEqual(untagged.tags, [], "Tag not removed from the identity");
+
+    console.log("✅ Tag index stays consistent");
+  });
+
+  it("32. Identities keep an append-only history of their metadata!", async () => {
+    // --- Arrange ---
+    const agentKey = anchor.web3.Keypair.generate();
+    await airdrop(agentKey.publicKey);
+    const { mint, identityPda: agentIdentityPda } = await registerAgent(agentKey, "versioned_agent");
+    const registered = await program.account.identityAccount.fetch(agentIdentityPda);
+
+    const uris = ["https://arweave.net/profile-v1", "https://arweave.net/profile-v2"];
+    const hashOf = (uri: string) => Array.from(createHash("sha256").update(uri).digest());
+
+    // --- Act ---
+    for (const [version, uri] of uris.entries()) {
+      await program.methods
+        .updateIdentity(uri, hashOf(uri), null)
+        .accounts({
+          signer: agentKey.publicKey,
+          identityAccount: agentIdentityPda,
+          displayNameClaim: usernameClaimPda("versioned_agent"),
+          metadataVersion: metadataVersionPdaFor(agentIdentityPda, version),
+          mint: mint,
+        } as any)
+        .signers([agentKey])
+        .rpc();
+    }
+
+    // --- Assert ---
+    const current = await program.account.identityAccount.fetch(agentIdentityPda);
+    assert.equal(current.metadataVersion, 2, "Version counter mismatch");
+    assert.strictEqual(current.uri, uris[1], "Current URI mismatch");
+
+    // Version 0 is the registration profile, version 1 the first update
+    const v0 = await program.account.metadataVersion.fetch(metadataVersionPdaFor(agentIdentityPda, 0));
+    const v1 = await program.account.metadataVersion.fetch(metadataVersionPdaFor(agentIdentityPda, 1));
+    assert.strictEqual(v0.uri, testUri, "Version 0 URI mismatch");
+    assert.deepEqual(v0.metadataHash, registered.metadataHash, "Version 0 hash mismatch");
+    assert.ok(v0.slot.eq(registered.metadataSlot), "Version 0 start slot mismatch");
+    assert.strictEqual(v1.uri, uris[0], "Version 1 URI mismatch");
+    assert.deepEqual(v1.metadataHash, hashOf(uris[0]), "Version 1 hash mismatch");
+
+    // The versions cover consecutive slot ranges, ending at the current one
+    assert.ok(v0.replacedSlot.eq(v1.slot), "Gap between version 0 and 1");
+    assert.ok(v1.replacedSlot.eq(current.metadataSlot), "Gap between version 1 and the current one");
+
+    console.log("✅ Metadata history archived");
+  });
+
+  it("33. Payments for a listed service must match its price!", async () => {
+    // --- Arrange ---
+    const serviceId = 1;
+    const serviceIdSeed = Buffer.alloc(4);
+    serviceIdSeed.writeUInt32LE(serviceId);
+    const [servicePda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("service"), identityPda.toBuffer(), serviceIdSeed],
+      program.programId
+    );
+    const minPrice = new anchor.BN(1_000_000); // 1 USDC
+    const maxPrice = new anchor.BN(10_000_000); // 10 USDC
+    const serviceUri = "https://arweave.net/translation-service-json";
+
+    const pay = (amount: anchor.BN) =>
+      program.methods
+        .logServiceTransaction(amount)
+        .accounts({
+          payer: payerUser.publicKey,
+          authority: authority,
+          identityAccount: identityPda,
+          mint: mockUsdcMint,
+          paymentPolicy: paymentPolicyPdaFor(identityPda, mockUsdcMint),
+          service: servicePda,
+        } as any)
+        .signers([payerUser])
+        .rpc();
+
+    const expectError = async (promise: Promise<any>, code: string) => {
+      try {
+        await promise;
+        assert.fail(`Transaction should have failed (${code})!`);
+      } catch (err: any) {
+        assert.equal(
+          err.error?.errorCode?.code,
+          code,
+          `Expected program error '${code}', got: ${JSON.stringify(err.error)}`
+        );
+      }
+    };
+
+    // --- Act ---
+    await program.methods
+      .createService(serviceId, serviceUri, minPrice, maxPrice)
+      .accounts({
+        signer: authority,
+        identityAccount: identityPda,
+        service: servicePda,
+        mint: mockUsdcMint,
+      } as any)
+      .rpc();
+
+    const amount = new anchor.BN(5_000_000);
+    await pay(amount);
+
+    // --- Assert ---
+    const service = await program.account.serviceOffering.fetch(servicePda);
+    assert.ok(service.identity.equals(identityPda), "Identity mismatch");
+    assert.ok(service.mint.equals(mockUsdcMint), "Mint mismatch");
+    assert.strictEqual(service.uri, serviceUri, "URI mismatch");
+    assert.equal(service.totalTransactions.toNumber(), 1, "Service transactions not counted");
+    assert.ok(service.totalVolume.eq(amount), "Service volume not counted");
+
+    // Amounts outside the listed range are rejected
+    await expectError(pay(new anchor.BN(20_000_000)), "PriceOutOfRange");
+    await expectError(pay(new anchor.BN(1)), "PriceOutOfRange");
+
+    // Paused listings can't be paid for
+    await program.methods
+      .updateService(serviceUri, minPrice, maxPrice, false)
+      .accounts({ signer: authority, identityAccount: identityPda, service: servicePda } as any)
+      .rpc();
+    await expectError(pay(amount), "ServiceInactive");
+
+    // Invalid ranges can't be listed
+    await expectError(
+      program.methods
+        .updateService(serviceUri, maxPrice, minPrice, true)
+        .accounts({ signer: authority, identityAccount: identityPda, service: servicePda } as any)
+        .rpc(),
+      "InvalidPriceRange"
+    );
+
+    await program.methods
+      .closeService()
+      .accounts({ signer: authority, identityAccount: identityPda, service: servicePda } as any)
+      .rpc();
+    assert.isNull(await provider.connection.getAccountInfo(servicePda), "Service not closed");
+
+    console.log("✅ Service listing enforced");
+  });
+
+  it("34. Payments must be in an accepted mint and within its bounds!", async () => {
+    // --- Arrange ---
+    // A worthless mint the payer just created
+    const junkMint = await createMint(provider.connection, authorityKeypair, authority, null, 6);
+    await getOrCreateAssociatedTokenAccount(provider.connection, authorityKeypair, junkMint, authority);
+    const payerJunkAccount = (await getOrCreateAssociatedTokenAccount(
+      provider.connection, authorityKeypair, junkMint, payerUser.publicKey
+    )).address;
+    await mintTo(provider.connection, authorityKeypair, junkMint, payerJunkAccount, authority, 1_000_000 * 1_000_000);
+
+    const usdcPolicyPda = paymentPolicyPdaFor(identityPda, mockUsdcMint);
+    const pay = (amount: anchor.BN, mint: anchor.web3.PublicKey, paymentPolicy: anchor.web3.PublicKey | null) =>
+      program.methods
+        .logServiceTransaction(amount)
+        .accounts({
+          payer: payerUser.publicKey,
+          authority: authority,
+          identityAccount: identityPda,
+          mint: mint,
+          paymentPolicy: paymentPolicy,
+          service: null,
+        } as any)
+        .signers([payerUser])
+        .rpc();
+
+    const expectError = async (promise: Promise<any>, code: string) => {
+      try {
+        await promise;
+        assert.fail(`Transaction should have failed (${code})!`);
+      } catch (err: any) {
+        assert.equal(
+          err.error?.errorCode?.code,
+          code,
+          `Expected program error '${code}', got: ${JSON.stringify(err.error)}`
+        );
+      }
+    };
+
+    // --- Act ---
+    // Narrow the accepted USDC amounts to 1-100 USDC
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(1_000_000), new anchor.BN(100_000_000))
+      .accounts({
+        signer: authority,
+        payer: authority,
+        identityAccount: identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: usdcPolicyPda,
+      } as any)
+      .rpc();
+
+    // --- Assert ---
+    const policy = await program.account.paymentPolicy.fetch(usdcPolicyPda);
+    assert.ok(policy.minAmount.eq(new anchor.BN(1_000_000)), "Minimum not updated");
+    assert.ok(policy.maxAmount.eq(new anchor.BN(100_000_000)), "Maximum not updated");
+
+    const reputationPre = await program.account.reputationAccount.fetch(reputationPda);
+
+    // The junk mint can't inflate the agent's volume
+    await expectError(pay(new anchor.BN(500_000 * 1_000_000), junkMint, null), "MintNotAccepted");
+    await expectError(pay(new anchor.BN(1), mockUsdcMint, usdcPolicyPda), "PaymentBelowMinimum");
+    await expectError(pay(new anchor.BN(200_000_000), mockUsdcMint, usdcPolicyPda), "PaymentAboveMaximum");
+
+    const reputationPost = await program.account.reputationAccount.fetch(reputationPda);
+    assert.ok(reputationPost.totalVolume.eq(reputationPre.totalVolume), "Rejected payments changed the volume");
+
+    // Removing the policy stops the agent accepting the mint
+    await program.methods
+      .removePaymentPolicy()
+      .accounts({ signer: authority, identityAccount: identityPda, paymentPolicy: usdcPolicyPda } as any)
+      .rpc();
+    await expectError(pay(new anchor.BN(5_000_000), mockUsdcMint, null), "MintNotAccepted");
+
+    console.log("✅ Payment rules enforced");
+  });
+
+  it("35. Only canonical mints count towards reputation, every mint has its own volume!", async () => {
+    // --- Arrange ---
+    // A 9-decimal token whose base units are worth far less than USDC's
+    const memeMint = await createMint(provider.connection, authorityKeypair, authority, null, 9);
+    await getOrCreateAssociatedTokenAccount(provider.connection, authorityKeypair, memeMint, authority);
+    const payerMemeAccount = (await getOrCreateAssociatedTokenAccount(
+      provider.connection, authorityKeypair, memeMint, payerUser.publicKey
+    )).address;
+    await mintTo(provider.connection, authorityKeypair, memeMint, payerMemeAccount, authority, 1_000 * 1_000_000_000);
+
+    const memePolicyPda = paymentPolicyPdaFor(identityPda, memeMint);
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(1), new anchor.BN(1_000 * 1_000_000_000))
+      .accounts({
+        signer: authority,
+        payer: authority,
+        identityAccount: identityPda,
+        mint: memeMint,
+        paymentPolicy: memePolicyPda,
+      } as any)
+      .rpc();
+
+    const [memeVolumePda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("mint_volume"), identityPda.toBuffer(), memeMint.toBuffer()],
+      program.programId
+    );
+    const reputationPre = await program.account.reputationAccount.fetch(reputationPda);
+
+    // --- Act ---
+    const amount = new anchor.BN(50 * 1_000_000_000);
+    await program.methods
+      .logServiceTransaction(amount)
+      .accounts({
+        payer: payerUser.publicKey,
+        authority: authority,
+        identityAccount: identityPda,
+        mint: memeMint,
+        mintVolume: memeVolumePda,
+        paymentPolicy: memePolicyPda,
+        service: null,
+      } as any)
+      .signers([payerUser])
+      .rpc();
+
+    // --- Assert ---
+    const memeVolume = await program.account.mintVolume.fetch(memeVolumePda);
+    assert.ok(memeVolume.mint.equals(memeMint), "Mint mismatch");
+    assert.equal(memeVolume.totalTransactions.toNumber(), 1, "Mint transactions not recorded");
+    assert.ok(memeVolume.totalVolume.eq(amount), "Mint volume not recorded");
+
+    const reputationPost = await program.account.reputationAccount.fetch(reputationPda);
+    assert.ok(reputationPost.totalTransactions.eq(reputationPre.totalTransactions), "Non-canonical payment counted");
+    assert.ok(reputationPost.totalVolume.eq(reputationPre.totalVolume), "Non-canonical volume counted");
+
+    // Only the admin manages canonical mints
+    try {
+      await program.methods
+        .addCanonicalMint(memeMint)
+        .accounts({ admin: payerUser.publicKey } as any)
+        .signers([payerUser])
+        .rpc();
+      assert.fail("A non-admin should not be able to add canonical mints!");
+    } catch (err: any) {
+      assert.equal(err.error?.errorCode?.code, "Unauthorized", `Unexpected error: ${JSON.stringify(err.error)}`);
+    }
+
+    console.log("✅ Per-mint volumes tracked, reputation counts canonical mints only");
+  });
+
+  it("36. A zero-amount payment doesn't earn the right to review!", async () => {
+    // --- Arrange ---
+    const freeloader = anchor.web3.Keypair.generate();
+    await airdrop(freeloader.publicKey);
+    await getOrCreateAssociatedTokenAccount(provider.connection, authorityKeypair, mockUsdcMint, freeloader.publicKey);
+
+    // The agent accepts mock USDC again, with no minimum
+    const usdcPolicyPda = paymentPolicyPdaFor(identityPda, mockUsdcMint);
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(0), new anchor.BN(1000 * 1_000_000))
+      .accounts({
+        signer: authority,
+        payer: authority,
+        identityAccount: identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: usdcPolicyPda,
+      } as any)
+      .rpc();
+
+    // --- Act & Assert ---
+    try {
+      await program.methods
+        .logServiceTransaction(new anchor.BN(0))
+        .accounts({
+          payer: freeloader.publicKey,
+          authority: authority,
+          identityAccount: identityPda,
+          mint: mockUsdcMint,
+          paymentPolicy: usdcPolicyPda,
+          service: null,
+        } as any)
+        .signers([freeloader])
+        .rpc();
+      assert.fail("A zero-amount payment should have failed!");
+    } catch (err: any) {
+      assert.equal(err.error?.errorCode?.code, "ZeroPaymentAmount", `Unexpected error: ${JSON.stringify(err.error)}`);
+    }
+
+    try {
+      await program.methods
+        .submitReview(5, "https://arweave.net/free-review")
+        .accounts({
+          reviewer: freeloader.publicKey,
+          authority: authority,
+          identityAccount: identityPda,
+        } as any)
+        .signers([freeloader])
+        .rpc();
+      assert.fail("A reviewer without a paid transaction should have been rejected!");
+    } catch (err: any) {
+      assert.equal(err.error?.errorCode?.code, "NoPaidTransaction", `Unexpected error: ${JSON.stringify(err.error)}`);
+    }
+
+    console.log("✅ Zero-amount payments rejected");
+  });
+
+  it("37. Display names can't impersonate the registry or another agent!", async () => {
+    // --- Arrange ---
+    const agentKey = anchor.web3.Keypair.generate();
+    await airdrop(agentKey.publicKey);
+    const { mint, identityPda: agentIdentityPda } = await registerAgent(agentKey, "display_agent");
+
+    const rename = (displayName: string, version: number, previousDisplayName: string | null = null) =>
+      program.methods
+        .updateIdentity(testUri, testMetadataHash, displayName)
+        .accounts({
+          signer: agentKey.publicKey,
+          identityAccount: agentIdentityPda,
+          displayNameClaim: displayNameClaimPda(displayName),
+          previousDisplayNameClaim: previousDisplayName ? displayNameClaimPda(previousDisplayName) : null,
+          metadataVersion: metadataVersionPdaFor(agentIdentityPda, version),
+          mint: mint,
+        } as any)
+        .signers([agentKey])
+        .rpc();
+
+    const expectError = async (promise: Promise<any>, code: string) => {
+      try {
+        await promise;
+        assert.fail(`Transaction should have failed (${code})!`);
+      } catch (err: any) {
+        assert.equal(
+          err.error?.errorCode?.code,
+          code,
+          `Expected program error '${code}', got: ${JSON.stringify(err.error)}`
+        );
+      }
+    };
+
+    // --- Act & Assert ---
+    // Reserved words, in any case or spacing
+    await expectError(rename("Admin", 0), "ReservedUsername");
+    await expectError(rename("Official Registry", 0), "ReservedUsername");
+
+    // Another agent's username (registered in test 1)
+    await expectError(rename("SOL USER 123", 0), "DisplayNameTaken");
+
+    // Lowercasing can grow a name past the limit: 16 x "İ" is 32 bytes, but its key is 48
+    await expectError(rename("İ".repeat(16), 0), "DisplayNameTooLong");
+
+    // Its own username, or a free name, are fine
+    await rename("Display Agent", 0);
+    await rename("Display.Agent", 1);
+    const identity = await program.account.identityAccount.fetch(agentIdentityPda);
+    assert.strictEqual(identity.displayName, "Display.Agent", "Display name not updated");
+
+    // A free name is claimed, so it can't be registered as a username
+    await rename("Shown Agent", 2);
+    const squatter = anchor.web3.Keypair.generate();
+    await airdrop(squatter.publicKey);
+    await expectError(registerAgent(squatter, "shown_agent"), "UsernameTaken");
+
+    // Nor displayed by another identity
+    const otherKey = anchor.web3.Keypair.generate();
+    await airdrop(otherKey.publicKey);
+    const other = await registerAgent(otherKey, "other_display_agent");
+    await expectError(
+      program.methods
+        .updateIdentity(testUri, testMetadataHash, "Shown-Agent")
+        .accounts({
+          signer: otherKey.publicKey,
+          identityAccount: other.identityPda,
+          displayNameClaim: displayNameClaimPda("Shown-Agent"),
+          metadataVersion: metadataVersionPdaFor(other.identityPda, 0),
+          mint: other.mint,
+        } as any)
+        .signers([otherKey])
+        .rpc(),
+      "DisplayNameTaken"
+    );
+
+    // Renaming releases the old claim
+    await expectError(rename("Display Agent", 3), "DisplayNameClaimMismatch");
+    await rename("Display Agent", 3, "Shown Agent");
+    assert.isNull(
+      await provider.connection.getAccountInfo(displayNameClaimPda("Shown Agent")),
+      "Old display name claim not released"
+    );
+
+    // A closed identity's retired username can't come back as a display name
+    const retiringKey = anchor.web3.Keypair.generate();
+    await airdrop(retiringKey.publicKey);
+    const retiring = await registerAgent(retiringKey, "retiring_agent");
+    await program.methods
+      .closeIdentity()
+      .accounts({
+        authority: retiringKey.publicKey,
+        identityAccount: retiring.identityPda,
+        usernameClaim: usernameClaimPda("retiring_agent"),
+        mint: retiring.mint,
+      } as any)
+      .signers([retiringKey])
+      .rpc();
+    await expectError(rename("Retiring Agent", 4), "UsernameRetired");
+
+    console.log("✅ Impersonating display names rejected");
+  });
+
+  it("38. An authority change drops the old owner's operators and kill switch!", async () => {
+    // --- Arrange ---
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    const oldRuntime = anchor.web3.Keypair.generate();
+    const newRuntime = anchor.web3.Keypair.generate();
+    await airdrop(oldKey.publicKey);
+    await airdrop(newKey.publicKey);
+    const agentUsername = "handover_agent";
+    const agentIdentityPda = identityPdaFor(oldKey.publicKey);
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+    const OPERATOR_UPDATE_METADATA = 1 << 0;
+
+    const mintKeypair = anchor.web3.Keypair.generate();
+    await program.methods
+      .registerIdentity(agentUsername, testUri, testMetadataHash, { aiAgent: {} }, {
+        key: oldRuntime.publicKey,
+        permissions: OPERATOR_UPDATE_METADATA,
+        expiresAt: expiresAt,
+      })
+      .accounts({
+        authority: oldKey.publicKey,
+        payer: oldKey.publicKey,
+        identityAccount: agentIdentityPda,
+        ownedIdentity: await nextOwnedIdentityPda(oldKey.publicKey),
+        mint: mintKeypair.publicKey,
+        usernameClaim: usernameClaimPda(agentUsername),
+        collectionMint: collectionMint,
+      })
+      .signers([oldKey, mintKeypair])
+      .rpc();
+
+    await program.methods
+      .setSuspended(true)
+      .accounts({ authority: oldKey.publicKey, identityAccount: agentIdentityPda } as any)
+      .signers([oldKey])
+      .rpc();
+
+    await program.methods
+      .setAuthorityChangeDelay(new anchor.BN(0))
+      .accounts({ admin: authority } as any)
+      .rpc();
+
+    await program.methods
+      .proposeAuthorityChange(newKey.publicKey)
+      .accounts({ authority: oldKey.publicKey, identityAccount: agentIdentityPda } as any)
+      .signers([oldKey])
+      .rpc();
+
+    const accept = async (operator: any) =>
+      program.methods
+        .acceptAuthorityChange(operator)
+        .accounts({
+          newAuthority: newKey.publicKey,
+          oldAuthority: oldKey.publicKey,
+          identityAccount: agentIdentityPda,
+          newOwnedIdentity: await nextOwnedIdentityPda(newKey.publicKey),
+          usernameClaim: usernameClaimPda(agentUsername),
+          mint: mintKeypair.publicKey,
+        } as any)
+        .signers([newKey])
+        .rpc();
+
+    // --- Act & Assert ---
+    // An AI agent can't be taken over without a new runtime key
+    try {
+      await accept(null);
+      assert.fail("Transaction should have failed (no operator)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "OperatorRequired",
+        `Expected program error 'OperatorRequired', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    await accept({
+      key: newRuntime.publicKey,
+      permissions: OPERATOR_UPDATE_METADATA,
+      expiresAt: expiresAt,
+    });
+
+    const identity = await program.account.identityAccount.fetch(agentIdentityPda);
+    assert.ok(identity.authority.equals(newKey.publicKey), "Authority not changed");
+    assert.strictEqual(identity.operators.length, 1, "Old operators carried over");
+    assert.ok(identity.operators[0].key.equals(newRuntime.publicKey), "New operator not set");
+    assert.isFalse(identity.suspended, "Old kill switch carried over");
+
+    console.log("✅ Operators reset on authority change");
+  });
+
+  it("39. Organization admins keep their role through an authority change!", async () => {
+    // --- Arrange ---
+    const orgName = "relay_agents";
+    const [orgPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("organization"), createHash("sha256").update(orgName).digest()],
+      program.programId
+    );
+    const membershipPda = (identity: anchor.web3.PublicKey) =>
+      anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("org_member"), orgPda.toBuffer(), identity.toBuffer()],
+        program.programId
+      )[0];
+
+    const oldAdmin = anchor.web3.Keypair.generate();
+    const newAdmin = anchor.web3.Keypair.generate();
+    const memberKey = anchor.web3.Keypair.generate();
+    await airdrop(oldAdmin.publicKey);
+    await airdrop(newAdmin.publicKey);
+    await airdrop(memberKey.publicKey);
+    const orgIdentity = await registerAgent(oldAdmin, "relay_org", { organization: {} });
+    const member = await registerAgent(memberKey, "relay_member");
+
+    await program.methods
+      .createOrganization(orgName, "https://arweave.net/relay-json")
+      .accounts({
+        authority: oldAdmin.publicKey,
+        identityAccount: orgIdentity.identityPda,
+        organization: orgPda,
+        membership: membershipPda(orgIdentity.identityPda),
+      } as any)
+      .signers([oldAdmin])
+      .rpc();
+
+    // The organization identity moves to a new key
+    await changeAuthority(oldAdmin, newAdmin, "relay_org", orgIdentity);
+
+    const join = (admin: anchor.web3.Keypair) =>
+      program.methods
+        .joinOrganization({ member: {} })
+        .accounts({
+          authority: memberKey.publicKey,
+          identityAccount: member.identityPda,
+          admin: admin.publicKey,
+          adminIdentity: orgIdentity.identityPda,
+          adminMembership: membershipPda(orgIdentity.identityPda),
+          organization: orgPda,
+          membership: membershipPda(member.identityPda),
+        } as any)
+        .signers([memberKey, admin])
+        .rpc();
+
+    // --- Act & Assert ---
+    // The old key no longer speaks for the admin identity
+    try {
+      await join(oldAdmin);
+      assert.fail("Transaction should have failed (old admin key)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "NotOrganizationAdmin",
+        `Expected program error 'NotOrganizationAdmin', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    // The new key does
+    await join(newAdmin);
+
+    const orgData = await program.account.organizationAccount.fetch(orgPda);
+    assert.strictEqual(orgData.memberCount, 2, "New admin could not admit a member");
+
+    console.log("✅ Organization admin survived the authority change");
+  });
+
+  it("40. A parent can still revoke its sub-identities after an authority change!", async () => {
+    // --- Arrange ---
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    const childKey = anchor.web3.Keypair.generate();
+    await airdrop(oldKey.publicKey);
+    await airdrop(newKey.publicKey);
+    const parent = await registerAgent(oldKey, "vouching_parent");
+
+    const childUsername = "vouched_child";
+    const childIdentityPda = identityPdaFor(childKey.publicKey);
+    const childMint = anchor.web3.Keypair.generate();
+    await program.methods
+      .registerSubIdentity(childUsername, testUri, testMetadataHash, { human: {} }, null)
+      .accounts({
+        register: {
+          authority: childKey.publicKey,
+          payer: oldKey.publicKey,
+          identityAccount: childIdentityPda,
+          ownedIdentity: await nextOwnedIdentityPda(childKey.publicKey),
+          mint: childMint.publicKey,
+          usernameClaim: usernameClaimPda(childUsername),
+          collectionMint: collectionMint,
+        },
+        parentSigner: oldKey.publicKey,
+        parentIdentity: parent.identityPda,
+      } as any)
+      .signers([oldKey, childKey, childMint])
+      .rpc();
+
+    // The parent identity moves to a new key
+    await changeAuthority(oldKey, newKey, "vouching_parent", parent);
+
+    // --- Act ---
+    // The child's parent link still resolves, and the new key can revoke it
+    const childData = await program.account.identityAccount.fetch(childIdentityPda);
+    assert.ok(childData.parent.equals(parent.identityPda), "Parent link changed");
+
+    await program.methods
+      .revokeSubIdentity()
+      .accounts({
+        parentSigner: newKey.publicKey,
+        parentIdentity: parent.identityPda,
+        childIdentity: childIdentityPda,
+      } as any)
+      .signers([newKey])
+      .rpc();
+
+    // --- Assert ---
+    const revokedData = await program.account.identityAccount.fetch(childIdentityPda);
+    assert.isTrue(revokedData.revoked, "Child not revoked after the parent moved");
+
+    console.log("✅ Sub-identity revoked after the parent's authority change");
+  });
+
+  it("41. Service endpoints stay with an identity through an authority change!", async () => {
+    // --- Arrange ---
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    await airdrop(oldKey.publicKey);
+    await airdrop(newKey.publicKey);
+    const agent = await registerAgent(oldKey, "moving_endpoints");
+    const [endpointsPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("endpoints"), agent.identityPda.toBuffer()],
+      program.programId
+    );
+    const accountsFor = (signer: anchor.web3.Keypair) =>
+      ({ signer: signer.publicKey, identityAccount: agent.identityPda, endpoints: endpointsPda } as any);
+
+    await program.methods.initializeEndpoints().accounts(accountsFor(oldKey)).signers([oldKey]).rpc();
+    await program.methods
+      .addEndpoint({ x402: {} }, "https://agent.example.com/x402", "translate")
+      .accounts(accountsFor(oldKey))
+      .signers([oldKey])
+      .rpc();
+
+    // --- Act ---
+    await changeAuthority(oldKey, newKey, "moving_endpoints", agent);
+
+    // The new key keeps managing the same list
+    await program.methods
+      .addEndpoint({ mcp: {} }, "https://agent.example.com/mcp", "tools")
+      .accounts(accountsFor(newKey))
+      .signers([newKey])
+      .rpc();
+
+    // --- Assert ---
+    const data = await program.account.serviceEndpoints.fetch(endpointsPda);
+    assert.ok(data.identity.equals(agent.identityPda), "Endpoints detached from the identity");
+    assert.deepEqual(data.endpoints.map((e: any) => e.label), ["translate", "tools"], "Endpoints not kept");
+
+    console.log("✅ Endpoints kept through an authority change");
+  });
+
+  it("42. An identity can't be closed while it has open service listings!", async () => {
+    // --- Arrange ---
+    const agentKey = anchor.web3.Keypair.generate();
+    await airdrop(agentKey.publicKey);
+    const agentUsername = "listing_agent";
+    const agent = await registerAgent(agentKey, agentUsername);
+    const serviceIdSeed = Buffer.alloc(4);
+    serviceIdSeed.writeUInt32LE(7);
+    const [servicePda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("service"), agent.identityPda.toBuffer(), serviceIdSeed],
+      program.programId
+    );
+
+    await program.methods
+      .createService(7, "https://arweave.net/listing-json", new anchor.BN(1), new anchor.BN(10))
+      .accounts({
+        signer: agentKey.publicKey,
+        identityAccount: agent.identityPda,
+        service: servicePda,
+        mint: mockUsdcMint,
+      } as any)
+      .signers([agentKey])
+      .rpc();
+    assert.strictEqual(
+      (await program.account.identityAccount.fetch(agent.identityPda)).serviceCount,
+      1,
+      "Listing not counted"
+    );
+
+    const close = () =>
+      program.methods
+        .closeIdentity()
+        .accounts({
+          authority: agentKey.publicKey,
+          identityAccount: agent.identityPda,
+          usernameClaim: usernameClaimPda(agentUsername),
+          reputationAccount: agent.reputationPda,
+          mint: agent.mint,
+        } as any)
+        .signers([agentKey])
+        .rpc();
+
+    // --- Act & Assert ---
+    try {
+      await close();
+      assert.fail("Transaction should have failed (open listing)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "IdentityHasServices",
+        `Expected program error 'IdentityHasServices', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    // Once the listing is closed, so can the identity be
+    await program.methods
+      .closeService()
+      .accounts({ signer: agentKey.publicKey, identityAccount: agent.identityPda, service: servicePda } as any)
+      .signers([agentKey])
+      .rpc();
+    await close();
+
+    assert.isNull(await provider.connection.getAccountInfo(servicePda), "Listing not closed");
+    assert.isNull(await provider.connection.getAccountInfo(agent.identityPda), "Identity not closed");
+
+    console.log("✅ Listings must be closed before their identity");
+  });
+
+  it("43. Payment policies stay through an authority change and must be removed before closing!", async () => {
+    // --- Arrange ---
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    await airdrop(oldKey.publicKey);
+    await airdrop(newKey.publicKey);
+    const agentUsername = "policy_agent";
+    const agent = await registerAgent(oldKey, agentUsername);
+    const policyPda = paymentPolicyPdaFor(agent.identityPda, mockUsdcMint);
+
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(1), new anchor.BN(100_000_000))
+      .accounts({
+        signer: oldKey.publicKey,
+        payer: oldKey.publicKey,
+        identityAccount: agent.identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: policyPda,
+      } as any)
+      .signers([oldKey])
+      .rpc();
+    assert.strictEqual(
+      (await program.account.identityAccount.fetch(agent.identityPda)).paymentPolicyCount,
+      1,
+      "Policy not counted"
+    );
+
+    // --- Act ---
+    await changeAuthority(oldKey, newKey, agentUsername, agent);
+
+    // The new key is paid under the policy the old key set
+    await getOrCreateAssociatedTokenAccount(provider.connection, authorityKeypair, mockUsdcMint, newKey.publicKey);
+    await program.methods
+      .logServiceTransaction(new anchor.BN(1_000_000))
+      .accounts({
+        payer: payerUser.publicKey,
+        authority: newKey.publicKey,
+        identityAccount: agent.identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: policyPda,
+        service: null,
+      } as any)
+      .signers([payerUser])
+      .rpc();
+
+    // --- Assert ---
+    const close = () =>
+      program.methods
+        .closeIdentity()
+        .accounts({
+          authority: newKey.publicKey,
+          identityAccount: agent.identityPda,
+          usernameClaim: usernameClaimPda(agentUsername),
+          reputationAccount: agent.reputationPda,
+          mint: agent.mint,
+        } as any)
+        .signers([newKey])
+        .rpc();
+
+    try {
+      await close();
+      assert.fail("Transaction should have failed (open payment policy)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "IdentityHasPaymentPolicies",
+        `Expected program error 'IdentityHasPaymentPolicies', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    await program.methods
+      .removePaymentPolicy()
+      .accounts({ signer: newKey.publicKey, identityAccount: agent.identityPda, paymentPolicy: policyPda } as any)
+      .signers([newKey])
+      .rpc();
+    await close();
+
+    assert.isNull(await provider.connection.getAccountInfo(policyPda), "Policy not closed");
+    assert.isNull(await provider.connection.getAccountInfo(agent.identityPda), "Identity not closed");
+
+    console.log("✅ Payment policies kept through an authority change and closed before the identity");
+  });
+
+  it("44. Per-mint volume keeps counting in the same account after an authority change!", async () => {
+    // --- Arrange ---
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    await airdrop(oldKey.publicKey);
+    await airdrop(newKey.publicKey);
+    const agentUsername = "volume_agent";
+    const agent = await registerAgent(oldKey, agentUsername);
+    const policyPda = paymentPolicyPdaFor(agent.identityPda, mockUsdcMint);
+    const [mintVolumePda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("mint_volume"), agent.identityPda.toBuffer(), mockUsdcMint.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(1), new anchor.BN(100_000_000))
+      .accounts({
+        signer: oldKey.publicKey,
+        payer: oldKey.publicKey,
+        identityAccount: agent.identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: policyPda,
+      } as any)
+      .signers([oldKey])
+      .rpc();
+
+    const pay = (recipient: anchor.web3.PublicKey, amount: anchor.BN) =>
+      program.methods
+        .logServiceTransaction(amount)
+        .accounts({
+          payer: payerUser.publicKey,
+          authority: recipient,
+          identityAccount: agent.identityPda,
+          mint: mockUsdcMint,
+          paymentPolicy: policyPda,
+          service: null,
+        } as any)
+        .signers([payerUser])
+        .rpc();
+
+    await getOrCreateAssociatedTokenAccount(provider.connection, authorityKeypair, mockUsdcMint, oldKey.publicKey);
+    await pay(oldKey.publicKey, new anchor.BN(2_000_000));
+
+    // --- Act ---
+    await changeAuthority(oldKey, newKey, agentUsername, agent);
+
+    await getOrCreateAssociatedTokenAccount(provider.connection, authorityKeypair, mockUsdcMint, newKey.publicKey);
+    await pay(newKey.publicKey, new anchor.BN(3_000_000));
+
+    // --- Assert ---
+    const volume = await program.account.mintVolume.fetch(mintVolumePda);
+    assert.strictEqual(volume.totalTransactions.toNumber(), 2, "Payments split across accounts");
+    assert.strictEqual(volume.totalVolume.toNumber(), 5_000_000, "Volume split across accounts");
+
+    console.log("✅ Per-mint volume kept through an authority change");
+  });
+
+  it("45. The reputation follows an authority change even when the client leaves it out!", async () => {
+    // --- Arrange ---
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    await airdrop(oldKey.publicKey);
+    await airdrop(newKey.publicKey);
+    const agentUsername = "implicit_reputation_agent";
+    const agent = await registerAgent(oldKey, agentUsername);
+    const policyPda = paymentPolicyPdaFor(agent.identityPda, mockUsdcMint);
+
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(1), new anchor.BN(100_000_000))
+      .accounts({
+        signer: oldKey.publicKey,
+        payer: oldKey.publicKey,
+        identityAccount: agent.identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: policyPda,
+      } as any)
+      .signers([oldKey])
+      .rpc();
+
+    await program.methods
+      .setAuthorityChangeDelay(new anchor.BN(0))
+      .accounts({ admin: authority } as any)
+      .rpc();
+
+    await program.methods
+      .proposeAuthorityChange(newKey.publicKey)
+      .accounts({ authority: oldKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([oldKey])
+      .rpc();
+
+    // --- Act ---
+    // No reputation account is passed; the client resolves the PDA itself
+    await program.methods
+      .acceptAuthorityChange(null)
+      .accounts({
+        newAuthority: newKey.publicKey,
+        oldAuthority: oldKey.publicKey,
+        identityAccount: agent.identityPda,
+        newOwnedIdentity: await nextOwnedIdentityPda(newKey.publicKey),
+        usernameClaim: usernameClaimPda(agentUsername),
+        mint: agent.mint,
+      } as any)
+      .signers([newKey])
+      .rpc();
+
+    // --- Assert ---
+    const reputation = await program.account.reputationAccount.fetch(agent.reputationPda);
+    assert.ok(reputation.authority.equals(newKey.publicKey), "Reputation authority left stale");
+
+    // The new key can still be paid and reviewed
+    await getOrCreateAssociatedTokenAccount(provider.connection, authorityKeypair, mockUsdcMint, newKey.publicKey);
+    await program.methods
+      .logServiceTransaction(new anchor.BN(1_000_000))
+      .accounts({
+        payer: payerUser.publicKey,
+        authority: newKey.publicKey,
+        identityAccount: agent.identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: policyPda,
+        service: null,
+      } as any)
+      .signers([payerUser])
+      .rpc();
+
+    await program.methods
+      .submitReview(4, "https://arweave.net/handover-review")
+      .accounts({
+        reviewer: payerUser.publicKey,
+        authority: newKey.publicKey,
+        identityAccount: agent.identityPda,
+      } as any)
+      .signers([payerUser])
+      .rpc();
+
+    const reputationPost = await program.account.reputationAccount.fetch(agent.reputationPda);
+    assert.strictEqual(reputationPost.totalTransactions.toNumber(), 1, "Payment not counted");
+    assert.strictEqual(reputationPost.totalReviews.toNumber(), 1, "Review not counted");
+
+    console.log("✅ Reputation moved with the identity without being passed");
+  });
+
+  it("46. A member can't close its identity while in an organization, and its rent follows an authority change!", async () => {
+    // --- Arrange ---
+    const orgName = "moving_members";
+    const [orgPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("organization"), createHash("sha256").update(orgName).digest()],
+      program.programId
+    );
+    const membershipPda = (identity: anchor.web3.PublicKey) =>
+      anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("org_member"), orgPda.toBuffer(), identity.toBuffer()],
+        program.programId
+      )[0];
+
+    const orgAdmin = anchor.web3.Keypair.generate();
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    await airdrop(orgAdmin.publicKey);
+    await airdrop(oldKey.publicKey);
+    await airdrop(newKey.publicKey);
+    const orgIdentity = await registerAgent(orgAdmin, "moving_org", { organization: {} });
+    const memberUsername = "moving_member";
+    const member = await registerAgent(oldKey, memberUsername);
+
+    await program.methods
+      .createOrganization(orgName, "https://arweave.net/moving-json")
+      .accounts({
+        authority: orgAdmin.publicKey,
+        identityAccount: orgIdentity.identityPda,
+        organization: orgPda,
+        membership: membershipPda(orgIdentity.identityPda),
+      } as any)
+      .signers([orgAdmin])
+      .rpc();
+
+    await program.methods
+      .joinOrganization({ member: {} })
+      .accounts({
+        authority: oldKey.publicKey,
+        identityAccount: member.identityPda,
+        admin: orgAdmin.publicKey,
+        adminIdentity: orgIdentity.identityPda,
+        adminMembership: membershipPda(orgIdentity.identityPda),
+        organization: orgPda,
+        membership: membershipPda(member.identityPda),
+      } as any)
+      .signers([oldKey, orgAdmin])
+      .rpc();
+    assert.strictEqual(
+      (await program.account.identityAccount.fetch(member.identityPda)).orgMembershipCount,
+      1,
+      "Membership not counted"
+    );
+
+    await changeAuthority(oldKey, newKey, memberUsername, member);
+
+    const close = () =>
+      program.methods
+        .closeIdentity()
+        .accounts({
+          authority: newKey.publicKey,
+          identityAccount: member.identityPda,
+          usernameClaim: usernameClaimPda(memberUsername),
+          reputationAccount: member.reputationPda,
+          mint: member.mint,
+        } as any)
+        .signers([newKey])
+        .rpc();
+
+    // --- Act & Assert ---
+    try {
+      await close();
+      assert.fail("Transaction should have failed (open membership)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "IdentityHasMemberships",
+        `Expected program error 'IdentityHasMemberships', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    const removeMember = (memberAuthority: anchor.web3.PublicKey) =>
+      program.methods
+        .removeMember()
+        .accounts({
+          admin: orgAdmin.publicKey,
+          adminIdentity: orgIdentity.identityPda,
+          adminMembership: membershipPda(orgIdentity.identityPda),
+          organization: orgPda,
+          membership: membershipPda(member.identityPda),
+          memberIdentity: member.identityPda,
+          memberAuthority: memberAuthority,
+        } as any)
+        .signers([orgAdmin])
+        .rpc();
+
+    // The key that joined no longer gets the rent
+    try {
+      await removeMember(oldKey.publicKey);
+      assert.fail("Transaction should have failed (stale member authority)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "ConstraintRaw",
+        `Expected error 'ConstraintRaw', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    const balancePre = await provider.connection.getBalance(newKey.publicKey);
+    await removeMember(newKey.publicKey);
+    const balancePost = await provider.connection.getBalance(newKey.publicKey);
+    assert.isAbove(balancePost, balancePre, "Rent didn't go to the current authority");
+
+    await close();
+    assert.isNull(await provider.connection.getAccountInfo(member.identityPda), "Identity not closed");
+
+    console.log("✅ Memberships block closing and refund the current authority");
+  });
+
+  it("47. A listed service can be paid without a matching payment policy!", async () => {
+    // --- Arrange ---
+    const agentKey = anchor.web3.Keypair.generate();
+    await airdrop(agentKey.publicKey);
+    const agent = await registerAgent(agentKey, "listed_only_agent");
+    await getOrCreateAssociatedTokenAccount(provider.connection, authorityKeypair, mockUsdcMint, agentKey.publicKey);
+
+    const serviceIdSeed = Buffer.alloc(4);
+    serviceIdSeed.writeUInt32LE(7);
+    const [servicePda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("service"), agent.identityPda.toBuffer(), serviceIdSeed],
+      program.programId
+    );
+    const policyPda = paymentPolicyPdaFor(agent.identityPda, mockUsdcMint);
+
+    await program.methods
+      .createService(7, "https://arweave.net/listed-service-json", new anchor.BN(5_000_000), new anchor.BN(10_000_000))
+      .accounts({
+        signer: agentKey.publicKey,
+        identityAccount: agent.identityPda,
+        service: servicePda,
+        mint: mockUsdcMint,
+      } as any)
+      .signers([agentKey])
+      .rpc();
+
+    const pay = (amount: anchor.BN, service: anchor.web3.PublicKey | null, paymentPolicy: anchor.web3.PublicKey | null) =>
+      program.methods
+        .logServiceTransaction(amount)
+        .accounts({
+          payer: payerUser.publicKey,
+          authority: agentKey.publicKey,
+          identityAccount: agent.identityPda,
+          mint: mockUsdcMint,
+          paymentPolicy: paymentPolicy,
+          service: service,
+        } as any)
+        .signers([payerUser])
+        .rpc();
+
+    const expectError = async (promise: Promise<any>, code: string) => {
+      try {
+        await promise;
+        assert.fail(`Transaction should have failed (${code})!`);
+      } catch (err: any) {
+        assert.equal(
+          err.error?.errorCode?.code,
+          code,
+          `Expected program error '${code}', got: ${JSON.stringify(err.error)}`
+        );
+      }
+    };
+
+    // --- Act & Assert ---
+    // No policy at all: the listing is payable, unlisted payments aren't
+    await pay(new anchor.BN(5_000_000), servicePda, null);
+    await expectError(pay(new anchor.BN(5_000_000), null, null), "MintNotAccepted");
+
+    // A policy whose bounds exclude the listed price doesn't block the listing
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(1), new anchor.BN(2_000_000))
+      .accounts({
+        signer: agentKey.publicKey,
+        payer: agentKey.publicKey,
+        identityAccount: agent.identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: policyPda,
+      } as any)
+      .signers([agentKey])
+      .rpc();
+
+    await pay(new anchor.BN(8_000_000), servicePda, policyPda);
+    await expectError(pay(new anchor.BN(8_000_000), null, policyPda), "PaymentAboveMaximum");
+
+    const service = await program.account.serviceOffering.fetch(servicePda);
+    assert.strictEqual(service.totalTransactions.toNumber(), 2, "Service payments not counted");
+    assert.strictEqual(service.totalVolume.toNumber(), 13_000_000, "Service volume not counted");
+
+    console.log("✅ Listed services are payable on their own terms");
+  });
+
+  it("48. An identity can't be closed while an authority change is pending!", async () => {
+    // --- Arrange ---
+    const agentKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    await airdrop(agentKey.publicKey);
+    const agentUsername = "pending_close_agent";
+    const agent = await registerAgent(agentKey, agentUsername);
+    const [pendingPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("authority_change"), agent.identityPda.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .proposeAuthorityChange(newKey.publicKey)
+      .accounts({ authority: agentKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([agentKey])
+      .rpc();
+
+    const close = () =>
+      program.methods
+        .closeIdentity()
+        .accounts({
+          authority: agentKey.publicKey,
+          identityAccount: agent.identityPda,
+          usernameClaim: usernameClaimPda(agentUsername),
+          reputationAccount: agent.reputationPda,
+          pendingAuthorityChange: pendingPda,
+          mint: agent.mint,
+        } as any)
+        .signers([agentKey])
+        .rpc();
+
+    // --- Act & Assert ---
+    try {
+      await close();
+      assert.fail("Transaction should have failed (pending authority change)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "AuthorityChangePending",
+        `Expected program error 'AuthorityChangePending', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    // Once the change is cancelled, its rent is back and the identity can be closed
+    await program.methods
+      .cancelAuthorityChange()
+      .accounts({ authority: agentKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([agentKey])
+      .rpc();
+    await close();
+
+    assert.isNull(await provider.connection.getAccountInfo(pendingPda), "Pending change not closed");
+    assert.isNull(await provider.connection.getAccountInfo(agent.identityPda), "Identity not closed");
+
+    console.log("✅ Pending authority changes must be cancelled before closing");
+  });
+
+  it("49. A member can leave an organization on its own, but the last admin can't!", async () => {
+    // --- Arrange ---
+    const orgName = "leaving_members";
+    const [orgPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("organization"), createHash("sha256").update(orgName).digest()],
+      program.programId
+    );
+    const membershipPda = (identity: anchor.web3.PublicKey) =>
+      anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("org_member"), orgPda.toBuffer(), identity.toBuffer()],
+        program.programId
+      )[0];
+
+    const orgAdmin = anchor.web3.Keypair.generate();
+    const memberKey = anchor.web3.Keypair.generate();
+    await airdrop(orgAdmin.publicKey);
+    await airdrop(memberKey.publicKey);
+    const orgIdentity = await registerAgent(orgAdmin, "leaving_org", { organization: {} });
+    const memberUsername = "leaving_member";
+    const member = await registerAgent(memberKey, memberUsername);
+
+    await program.methods
+      .createOrganization(orgName, "https://arweave.net/leaving-json")
+      .accounts({
+        authority: orgAdmin.publicKey,
+        identityAccount: orgIdentity.identityPda,
+        organization: orgPda,
+        membership: membershipPda(orgIdentity.identityPda),
+      } as any)
+      .signers([orgAdmin])
+      .rpc();
+
+    await program.methods
+      .joinOrganization({ member: {} })
+      .accounts({
+        authority: memberKey.publicKey,
+        identityAccount: member.identityPda,
+        admin: orgAdmin.publicKey,
+        adminIdentity: orgIdentity.identityPda,
+        adminMembership: membershipPda(orgIdentity.identityPda),
+        organization: orgPda,
+        membership: membershipPda(member.identityPda),
+      } as any)
+      .signers([memberKey, orgAdmin])
+      .rpc();
+
+    const leave = (key: anchor.web3.Keypair, identity: anchor.web3.PublicKey) =>
+      program.methods
+        .leaveOrganization()
+        .accounts({
+          authority: key.publicKey,
+          identityAccount: identity,
+          organization: orgPda,
+          membership: membershipPda(identity),
+        } as any)
+        .signers([key])
+        .rpc();
+
+    // --- Act ---
+    // No admin signs
+    await leave(memberKey, member.identityPda);
+
+    // --- Assert ---
+    assert.isNull(await provider.connection.getAccountInfo(membershipPda(member.identityPda)), "Membership not closed");
+    const orgData = await program.account.organizationAccount.fetch(orgPda);
+    assert.strictEqual(orgData.memberCount, 1, "Member count not decremented");
+    assert.strictEqual(
+      (await program.account.identityAccount.fetch(member.identityPda)).orgMembershipCount,
+      0,
+      "Identity membership count not decremented"
+    );
+
+    // The last admin can't walk away from the organization
+    try {
+      await leave(orgAdmin, orgIdentity.identityPda);
+      assert.fail("Transaction should have failed (last admin)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "LastOrganizationAdmin",
+        `Expected program error 'LastOrganizationAdmin', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    // Having left, the member can close its identity
+    await program.methods
+      .closeIdentity()
+      .accounts({
+        authority: memberKey.publicKey,
+        identityAccount: member.identityPda,
+        usernameClaim: usernameClaimPda(memberUsername),
+        reputationAccount: member.reputationPda,
+        mint: member.mint,
+      } as any)
+      .signers([memberKey])
+      .rpc();
+    assert.isNull(await provider.connection.getAccountInfo(member.identityPda), "Identity not closed");
+
+    console.log("✅ Members leave on their own, the last admin stays");
+  });
+
+  it("50. A parent can't be closed while it still vouches for sub-identities!", async () => {
+    // --- Arrange ---
+    const parentKey = anchor.web3.Keypair.generate();
+    await airdrop(parentKey.publicKey);
+    const parentUsername = "closing_parent";
+    const parent = await registerAgent(parentKey, parentUsername);
+
+    const registerChild = async (username: string) => {
+      const childKey = anchor.web3.Keypair.generate();
+      const childMint = anchor.web3.Keypair.generate();
+      await airdrop(childKey.publicKey);
+      await program.methods
+        .registerSubIdentity(username, testUri, testMetadataHash, { human: {} }, null)
+        .accounts({
+          register: {
+            authority: childKey.publicKey,
+            payer: parentKey.publicKey,
+            identityAccount: identityPdaFor(childKey.publicKey),
+            ownedIdentity: await nextOwnedIdentityPda(childKey.publicKey),
+            mint: childMint.publicKey,
+            usernameClaim: usernameClaimPda(username),
+            collectionMint: collectionMint,
+          },
+          parentSigner: parentKey.publicKey,
+          parentIdentity: parent.identityPda,
+        } as any)
+        .signers([parentKey, childKey, childMint])
+        .rpc();
+      return { key: childKey, username, identityPda: identityPdaFor(childKey.publicKey), mint: childMint.publicKey };
+    };
+
+    const revokedChild = await registerChild("revoked_child");
+    const closingChild = await registerChild("closing_child");
+    const subIdentityCount = async () =>
+      (await program.account.identityAccount.fetch(parent.identityPda)).subIdentityCount;
+    assert.strictEqual(await subIdentityCount(), 2, "Sub-identities not counted");
+
+    const close = (
+      key: anchor.web3.Keypair,
+      username: string,
+      identity: anchor.web3.PublicKey,
+      mint: anchor.web3.PublicKey,
+      parentIdentity: anchor.web3.PublicKey | null
+    ) =>
+      program.methods
+        .closeIdentity()
+        .accounts({
+          authority: key.publicKey,
+          identityAccount: identity,
+          parentIdentity: parentIdentity,
+          usernameClaim: usernameClaimPda(username),
+          mint: mint,
+        } as any)
+        .signers([key])
+        .rpc();
+
+    // --- Act & Assert ---
+    try {
+      await close(parentKey, parentUsername, parent.identityPda, parent.mint, null);
+      assert.fail("Transaction should have failed (vouched sub-identities)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "IdentityHasSubIdentities",
+        `Expected program error 'IdentityHasSubIdentities', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    // Revoking a child takes it out of the count
+    await program.methods
+      .revokeSubIdentity()
+      .accounts({
+        parentSigner: parentKey.publicKey,
+        parentIdentity: parent.identityPda,
+        childIdentity: revokedChild.identityPda,
+      } as any)
+      .signers([parentKey])
+      .rpc();
+    assert.strictEqual(await subIdentityCount(), 1, "Revoked child still counted");
+
+    // A vouched child must pass its parent when it closes
+    try {
+      await close(closingChild.key, closingChild.username, closingChild.identityPda, closingChild.mint, null);
+      assert.fail("Transaction should have failed (parent left out)!");
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "NotParentIdentity",
+        `Expected program error 'NotParentIdentity', got: ${JSON.stringify(err.error)}`
+      );
+    }
+    await close(closingChild.key, closingChild.username, closingChild.identityPda, closingChild.mint, parent.identityPda);
+    assert.strictEqual(await subIdentityCount(), 0, "Closed child still counted");
+
+    // With no vouched children left, the parent closes. The revoked child stays.
+    await close(parentKey, parentUsername, parent.identityPda, parent.mint, null);
+    assert.isNull(await provider.connection.getAccountInfo(parent.identityPda), "Parent not closed");
+    assert.isNotNull(await provider.connection.getAccountInfo(revokedChild.identityPda), "Revoked child was closed");
+
+    console.log("✅ Vouched sub-identities block closing their parent");
+  });
+
+  it("51. Closing an identity always closes its service endpoints!", async () => {
+    // --- Arrange ---
+    const agentKey = anchor.web3.Keypair.generate();
+    await airdrop(agentKey.publicKey);
+    const agentUsername = "closing_endpoints";
+    const agent = await registerAgent(agentKey, agentUsername);
+    const [endpointsPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("endpoints"), agent.identityPda.toBuffer()],
+      program.programId
+    );
+    const accounts = { signer: agentKey.publicKey, identityAccount: agent.identityPda, endpoints: endpointsPda } as any;
+
+    await program.methods.initializeEndpoints().accounts(accounts).signers([agentKey]).rpc();
+    await program.methods
+      .addEndpoint({ a2a: {} }, "https://agent.example.com/a2a", "chat")
+      .accounts(accounts)
+      .signers([agentKey])
+      .rpc();
+
+    // --- Act ---
+    // The client resolves the endpoints PDA itself, so it can't be left out
+    await program.methods
+      .closeIdentity()
+      .accounts({
+        authority: agentKey.publicKey,
+        identityAccount: agent.identityPda,
+        usernameClaim: usernameClaimPda(agentUsername),
+        mint: agent.mint,
+      } as any)
+      .signers([agentKey])
+      .rpc();
+
+    // --- Assert ---
+    assert.isNull(await provider.connection.getAccountInfo(endpointsPda), "Endpoints not closed");
+    assert.isNull(await provider.connection.getAccountInfo(agent.identityPda), "Identity not closed");
+
+    console.log("✅ Endpoints closed with the identity");
+  });
+
+  it("52. A key that closed an identity can't take over another one!", async () => {
+    // --- Arrange ---
+    const closedKey = anchor.web3.Keypair.generate();
+    const ownerKey = anchor.web3.Keypair.generate();
+    await airdrop(closedKey.publicKey);
+    await airdrop(ownerKey.publicKey);
+
+    const closedUsername = "tombstoned_key";
+    const closed = await registerAgent(closedKey, closedUsername);
+    await program.methods
+      .closeIdentity()
+      .accounts({
+        authority: closedKey.publicKey,
+        identityAccount: closed.identityPda,
+        usernameClaim: usernameClaimPda(closedUsername),
+        mint: closed.mint,
+      } as any)
+      .signers([closedKey])
+      .rpc();
+
+    const ownerUsername = "handover_target";
+    const agent = await registerAgent(ownerKey, ownerUsername);
+
+    await program.methods
+      .setAuthorityChangeDelay(new anchor.BN(0))
+      .accounts({ admin: authority } as any)
+      .rpc();
+
+    await program.methods
+      .proposeAuthorityChange(closedKey.publicKey)
+      .accounts({ authority: ownerKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([ownerKey])
+      .rpc();
+
+    // --- Act & Assert ---
+    // Taking over an identity would give the closed key a fresh start
+    try {
+      await program.methods
+        .acceptAuthorityChange(null)
+        .accounts({
+          newAuthority: closedKey.publicKey,
+          oldAuthority: ownerKey.publicKey,
+          identityAccount: agent.identityPda,
+          newOwnedIdentity: await nextOwnedIdentityPda(closedKey.publicKey),
+          reputationAccount: agent.reputationPda,
+          usernameClaim: usernameClaimPda(ownerUsername),
+          mint: agent.mint,
+        } as any)
+        .signers([closedKey])
+        .rpc();
+
+      assert.fail("Transaction should have failed (new key tombstoned)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "IdentityClosed",
+        `Expected program error 'IdentityClosed', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    const agentData = await program.account.identityAccount.fetch(agent.identityPda);
+    assert.ok(agentData.authority.equals(ownerKey.publicKey), "Identity moved to a tombstoned key");
+
+    console.log("✅ Tombstoned keys can't take over identities");
+  });
+
+  it("53. Rent freed by an operator removing an endpoint goes to the owner!", async () => {
+    // --- Arrange ---
+    const ownerKey = anchor.web3.Keypair.generate();
+    const operator = anchor.web3.Keypair.generate();
+    await airdrop(ownerKey.publicKey);
+    await airdrop(operator.publicKey);
+    const agent = await registerAgent(ownerKey, "endpoint_rent_agent");
+    const [endpointsPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("endpoints"), agent.identityPda.toBuffer()],
+      program.programId
+    );
+    const OPERATOR_MANAGE_ENDPOINTS = 1 << 2;
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+    await program.methods
+      .addOperator(operator.publicKey, OPERATOR_MANAGE_ENDPOINTS, expiresAt)
+      .accounts({ authority: ownerKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([ownerKey])
+      .rpc();
+
+    const accountsFor = (signer: anchor.web3.Keypair) =>
+      ({ signer: signer.publicKey, identityAccount: agent.identityPda, endpoints: endpointsPda } as any);
+    await program.methods.initializeEndpoints().accounts(accountsFor(ownerKey)).signers([ownerKey]).rpc();
+    await program.methods
+      .addEndpoint({ x402: {} }, "https://agent.example.com/x402", "translate")
+      .accounts(accountsFor(ownerKey))
+      .signers([ownerKey])
+      .rpc();
+
+    const ownerPre = await provider.connection.getBalance(ownerKey.publicKey);
+    const operatorPre = await provider.connection.getBalance(operator.publicKey);
+
+    // --- Act ---
+    await program.methods.removeEndpoint(0).accounts(accountsFor(operator)).signers([operator]).rpc();
+
+    // --- Assert ---
+    assert.isAbove(await provider.connection.getBalance(ownerKey.publicKey), ownerPre, "Owner not refunded");
+    assert.strictEqual(await provider.connection.getBalance(operator.publicKey), operatorPre, "Operator kept the rent");
+
+    console.log("✅ Freed endpoint rent goes to the owner");
+  });
+
+  it("54. Rent of a service closed by an operator goes to the owner!", async () => {
+    // --- Arrange ---
+    const ownerKey = anchor.web3.Keypair.generate();
+    const operator = anchor.web3.Keypair.generate();
+    await airdrop(ownerKey.publicKey);
+    await airdrop(operator.publicKey);
+    const agent = await registerAgent(ownerKey, "service_rent_agent");
+    const serviceIdSeed = Buffer.alloc(4);
+    serviceIdSeed.writeUInt32LE(1);
+    const [servicePda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("service"), agent.identityPda.toBuffer(), serviceIdSeed],
+      program.programId
+    );
+    const OPERATOR_MANAGE_SERVICES = 1 << 3;
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+    await program.methods
+      .addOperator(operator.publicKey, OPERATOR_MANAGE_SERVICES, expiresAt)
+      .accounts({ authority: ownerKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([ownerKey])
+      .rpc();
+    await program.methods
+      .createService(1, "https://arweave.net/listing-json", new anchor.BN(1), new anchor.BN(10))
+      .accounts({
+        signer: ownerKey.publicKey,
+        identityAccount: agent.identityPda,
+        service: servicePda,
+        mint: mockUsdcMint,
+      } as any)
+      .signers([ownerKey])
+      .rpc();
+
+    const ownerPre = await provider.connection.getBalance(ownerKey.publicKey);
+    const operatorPre = await provider.connection.getBalance(operator.publicKey);
+
+    // --- Act ---
+    await program.methods
+      .closeService()
+      .accounts({ signer: operator.publicKey, identityAccount: agent.identityPda, service: servicePda } as any)
+      .signers([operator])
+      .rpc();
+
+    // --- Assert ---
+    assert.isNull(await provider.connection.getAccountInfo(servicePda), "Service not closed");
+    assert.isAbove(await provider.connection.getBalance(ownerKey.publicKey), ownerPre, "Owner not refunded");
+    assert.strictEqual(await provider.connection.getBalance(operator.publicKey), operatorPre, "Operator kept the rent");
+
+    console.log("✅ Closed service rent goes to the owner");
+  });
+
+  it("55. Rent of a payment policy removed by an operator goes to the owner!", async () => {
+    // --- Arrange ---
+    const ownerKey = anchor.web3.Keypair.generate();
+    const operator = anchor.web3.Keypair.generate();
+    await airdrop(ownerKey.publicKey);
+    await airdrop(operator.publicKey);
+    const agent = await registerAgent(ownerKey, "policy_rent_agent");
+    const policyPda = paymentPolicyPdaFor(agent.identityPda, mockUsdcMint);
+    const OPERATOR_MANAGE_SERVICES = 1 << 3;
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+    await program.methods
+      .addOperator(operator.publicKey, OPERATOR_MANAGE_SERVICES, expiresAt)
+      .accounts({ authority: ownerKey.publicKey, identityAccount: agent.identityPda } as any)
+      .signers([ownerKey])
+      .rpc();
+    await program.methods
+      .setPaymentPolicy(new anchor.BN(1), new anchor.BN(1000 * 1_000_000))
+      .accounts({
+        signer: ownerKey.publicKey,
+        payer: ownerKey.publicKey,
+        identityAccount: agent.identityPda,
+        mint: mockUsdcMint,
+        paymentPolicy: policyPda,
+      } as any)
+      .signers([ownerKey])
+      .rpc();
+
+    const ownerPre = await provider.connection.getBalance(ownerKey.publicKey);
+    const operatorPre = await provider.connection.getBalance(operator.publicKey);
+
+    // --- Act ---
+    await program.methods
+      .removePaymentPolicy()
+      .accounts({ signer: operator.publicKey, identityAccount: agent.identityPda, paymentPolicy: policyPda } as any)
+      .signers([operator])
+      .rpc();
+
+    // --- Assert ---
+    assert.isNull(await provider.connection.getAccountInfo(policyPda), "Policy not closed");
+    assert.isAbove(await provider.connection.getBalance(ownerKey.publicKey), ownerPre, "Owner not refunded");
+    assert.strictEqual(await provider.connection.getBalance(operator.publicKey), operatorPre, "Operator kept the rent");
+
+    console.log("✅ Removed payment policy rent goes to the owner");
+  });
+
+  it("56. Received identities are listed under their new owner until closed!", async () => {
+    // --- Arrange ---
+    const oldKey = anchor.web3.Keypair.generate();
+    const newKey = anchor.web3.Keypair.generate();
+    await airdrop(oldKey.publicKey);
+    await airdrop(newKey.publicKey);
+    const agentUsername = "received_agent";
+    const agent = await registerAgent(oldKey, agentUsername);
+    const counterPdaFor = (owner: anchor.web3.PublicKey) =>
+      anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("identity_counter"), owner.toBuffer()],
+        program.programId
+      )[0];
+
+    // --- Act ---
+    await changeAuthority(oldKey, newKey, agentUsername, agent);
+
+    // --- Assert ---
+    // The new owner never registered, but can enumerate what it received
+    const newCounter = await program.account.identityCounter.fetch(counterPdaFor(newKey.publicKey));
+    assert.strictEqual(newCounter.count, 0, "New owner registered nothing");
+    assert.strictEqual(newCounter.ownedCount, 1, "Received identity not counted");
+    const entry = await program.account.ownedIdentity.fetch(ownedIdentityPdaFor(newKey.publicKey, 0));
+    assert.ok(entry.identity.equals(agent.identityPda), "Received identity not listed");
+    assert.strictEqual(
+      (await program.account.identityAccount.fetch(agent.identityPda)).ownedIndex,
+      0,
+      "Owned index not moved"
+    );
+
+    // The old owner's entry is closed, leaving a gap
+    const oldCounter = await program.account.identityCounter.fetch(counterPdaFor(oldKey.publicKey));
+    assert.strictEqual(oldCounter.ownedCount, 1, "Old owner's slots were reused");
+    assert.isNull(
+      await provider.connection.getAccountInfo(ownedIdentityPdaFor(oldKey.publicKey, 0)),
+      "Old owner still lists the identity"
+    );
+
+    // Closing the identity closes the new owner's entry
+    await program.methods
+      .closeIdentity()
+      .accounts({
+        authority: newKey.publicKey,
+        identityAccount: agent.identityPda,
+        usernameClaim: usernameClaimPda(agentUsername),
+        reputationAccount: agent.reputationPda,
+        mint: agent.mint,
+      } as any)
+      .signers([newKey])
+      .rpc();
+    assert.isNull(
+      await provider.connection.getAccountInfo(ownedIdentityPdaFor(newKey.publicKey, 0)),
+      "Closed identity still listed"
+    );
+
+    console.log("✅ Owned lists follow the identity");
+  });
+});
@@ -0,0 +1,339 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { IdentityRegister } from "../target/types/identity_register";
+import { MockCaller } from "../target/types/mock_caller";
+import { assert } from "chai";
+import { createHash } from "crypto";
+import {
+  getOrCreateAssociatedTokenAccount,
+  getAssociatedTokenAddressSync,
+  createMint,
+  mintTo,
+  getAccount,
+  TOKEN_PROGRAM_ID,
+  ASSOCIATED_TOKEN_PROGRAM_ID
+} from "@solana/spl-token";
+
+// These tests run after identity_register.ts, which creates the registry collection.
+describe("mock_caller", () => {
+  // Configure the client to use the local cluster.
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.IdentityRegister as Program<IdentityRegister>;
+  const mockCaller = anchor.workspace.MockCaller as Program<MockCaller>;
+
+  const wallet = provider.wallet as anchor.Wallet;
+  const owner = wallet.publicKey;
+  const ownerKeypair = (wallet as any).payer as anchor.web3.Keypair;
+
+  const TOKEN_METADATA_PROGRAM_ID = new anchor.web3.PublicKey(
+    "metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s"
+  );
+
+  const vaultUsername = "vault_agent";
+
+  // The vault PDA is owned by the mock program and is the agent's authority
+  const [vaultPda] = anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("vault"), owner.toBuffer()],
+    mockCaller.programId
+  );
+
+  const findPda = (seeds: Buffer[], programId = program.programId) =>
+    anchor.web3.PublicKey.findProgramAddressSync(seeds, programId)[0];
+
+  // The vault's first identity (index 0 as a little-endian u32)
+  const identityPda = findPda([Buffer.from("identity"), vaultPda.toBuffer(), Buffer.alloc(4)]);
+  const reputationPda = findPda([Buffer.from("reputation"), identityPda.toBuffer()]);
+  const vaultUsernameClaim = findPda([
+    Buffer.from("username"),
+    createHash("sha256").update(vaultUsername).digest()
+  ]);
+
+  it("1. Registers a PDA-owned identity through CPI!", async () => {
+    // --- Arrange ---
+    const configPda = findPda([Buffer.from("config")]);
+    const config = await program.account.registryConfig.fetch(configPda);
+    const collectionMint = config.collectionMint;
+    const mintKeypair = anchor.web3.Keypair.generate();
+    const mint = mintKeypair.publicKey;
+
+    // --- Act ---
+    await mockCaller.methods
+      .registerVaultIdentity(
+        vaultUsername,
+        "https://arweave.net/vault-profile-json",
+        Array.from(createHash("sha256").update('{"name":"vault"}').digest())
+      )
+      .accounts({
+        owner: owner,
+        identityCounter: findPda([Buffer.from("identity_counter"), vaultPda.toBuffer()]),
+        ownedIdentity: findPda([Buffer.from("owned_identity"), vaultPda.toBuffer(), Buffer.alloc(4)]),
+        identityAccount: identityPda,
+        usernameClaim: vaultUsernameClaim,
+        usernameTombstone: findPda([Buffer.from("username_tombstone"), vaultUsernameClaim.toBuffer()]),
+        tombstone: findPda([Buffer.from("tombstone"), vaultPda.toBuffer()]),
+        registryAuthority: findPda([Buffer.from("registry_authority")]),
+        mint: mint,
+        tokenAccount: getAssociatedTokenAddressSync(mint, vaultPda, true),
+        identityMintRecord: findPda([Buffer.from("identity_mint"), mint.toBuffer()]),
+        metadataAccount: findPda(
+          [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), mint.toBuffer()],
+          TOKEN_METADATA_PROGRAM_ID
+        ),
+        masterEditionAccount: findPda(
+          [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), mint.toBuffer(), Buffer.from("edition")],
+          TOKEN_METADATA_PROGRAM_ID
+        ),
+        config: configPda,
+        collectionMint: collectionMint,
+        collectionMetadata: findPda(
+          [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), collectionMint.toBuffer()],
+          TOKEN_METADATA_PROGRAM_ID
+        ),
+        collectionMasterEdition: findPda(
+          [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), collectionMint.toBuffer(), Buffer.from("edition")],
+          TOKEN_METADATA_PROGRAM_ID
+        ),
+        identityRegisterProgram: program.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      } as any)
+      .preInstructions([anchor.web3.ComputeBudgetProgram.setComputeUnitLimit({ units: 400_000 })])
+      .signers([mintKeypair])
+      .rpc();
+
+    // --- Assert ---
+    const identityData = await program.account.identityAccount.fetch(identityPda);
+    assert.ok(identityData.authority.equals(vaultPda), "Identity authority is not the vault PDA");
+    assert.strictEqual(identityData.username, vaultUsername, "Username mismatch");
+    assert.deepEqual(identityData.kind, { program: {} }, "Kind mismatch");
+
+    const nftAccount = await getAccount(provider.connection, getAssociatedTokenAddressSync(mint, vaultPda, true));
+    assert.strictEqual(nftAccount.amount.toString(), "1", "Vault does not hold the identity NFT");
+    assert.ok(nftAccount.isFrozen, "Vault identity NFT is not frozen");
+
+    console.log("✅ PDA-owned identity registered through CPI");
+  });
+
+  it("2. Initializes reputation for the PDA-owned identity through CPI!", async () => {
+    await mockCaller.methods
+      .initializeVaultReputation()
+      .accounts({
+        owner: owner,
+        identityAccount: identityPda,
+        reputationAccount: reputationPda,
+        identityRegisterProgram: program.programId,
+      } as any)
+      .rpc();
+
+    const reputationData = await program.account.reputationAccount.fetch(reputationPda);
+    assert.ok(reputationData.authority.equals(vaultPda), "Reputation authority is not the vault PDA");
+
+    console.log("✅ PDA-owned reputation initialized through CPI");
+  });
+
+  it("3. The PDA-owned identity receives service payments!", async () => {
+    // --- Arrange ---
+    const customer = anchor.web3.Keypair.generate();
+    const sig = await provider.connection.requestAirdrop(customer.publicKey, anchor.web3.LAMPORTS_PER_SOL);
+    await provider.connection.confirmTransaction(sig);
+
+    const usdcMint = await createMint(provider.connection, ownerKeypair, owner, null, 6);
+    const customerTokenAccount = (await getOrCreateAssociatedTokenAccount(
+      provider.connection, ownerKeypair, usdcMint, customer.publicKey
+    )).address;
+    // The vault is off-curve, so its token account must allow that
+    const vaultTokenAccount = (await getOrCreateAssociatedTokenAccount(
+      provider.connection, ownerKeypair, usdcMint, vaultPda, true
+    )).address;
+    await mintTo(provider.connection, ownerKeypair, usdcMint, customerTokenAccount, owner, 10 * 1_000_000);
+
+    // The mint counts towards reputation (the provider wallet is the registry admin)
+    await program.methods
+      .addCanonicalMint(usdcMint)
+      .accounts({ admin: owner } as any)
+      .rpc();
+
+    // The vault accepts the mint, with the policy set through CPI
+    const paymentPolicyPda = findPda([Buffer.from("payment_policy"), identityPda.toBuffer(), usdcMint.toBuffer()]);
+    await mockCaller.methods
+      .setVaultPaymentPolicy(new anchor.BN(1), new anchor.BN(10 * 1_000_000))
+      .accounts({
+        owner: owner,
+        identityAccount: identityPda,
+        mint: usdcMint,
+        paymentPolicy: paymentPolicyPda,
+        identityRegisterProgram: program.programId,
+      } as any)
+      .rpc();
+
+    // --- Act ---
+    const amount = new anchor.BN(5 * 1_000_000);
+    await program.methods
+      .logServiceTransaction(amount)
+      .accounts({
+        payer: customer.publicKey,
+        authority: vaultPda,
+        identityAccount: identityPda,
+        mint: usdcMint,
+        paymentPolicy: paymentPolicyPda,
+      } as any)
+      .signers([customer])
+      .rpc();
+
+    // --- Assert ---
+    const vaultBalance = await getAccount(provider.connection, vaultTokenAccount);
+    assert.strictEqual(vaultBalance.amount.toString(), amount.toString(), "Vault was not paid");
+
+    const reputationData = await program.account.reputationAccount.fetch(reputationPda);
+    assert.strictEqual(reputationData.totalTransactions.toNumber(), 1, "Transaction not recorded");
+    assert.ok(reputationData.totalVolume.eq(amount), "Volume not recorded");
+
+    console.log("✅ PDA-owned identity received a payment");
+  });
+
+  it("4. A wallet can't register as a program identity through a wrapper program!", async () => {
+    // --- Arrange ---
+    const walletKey = anchor.web3.Keypair.generate();
+    const sig = await provider.connection.requestAirdrop(walletKey.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL);
+    await provider.connection.confirmTransaction(sig);
+
+    const configPda = findPda([Buffer.from("config")]);
+    const config = await program.account.registryConfig.fetch(configPda);
+    const collectionMint = config.collectionMint;
+    const mintKeypair = anchor.web3.Keypair.generate();
+    const mint = mintKeypair.publicKey;
+    const username = "wrapped_wallet";
+    const usernameClaim = findPda([Buffer.from("username"), createHash("sha256").update(username).digest()]);
+
+    // --- Act & Assert ---
+    // The call reaches the registry through CPI, but the authority is on the curve
+    try {
+      await mockCaller.methods
+        .forwardProgramRegistration(
+          username,
+          "https://arweave.net/wallet-profile-json",
+          Array.from(createHash("sha256").update('{"name":"wallet"}').digest())
+        )
+        .accounts({
+          wallet: walletKey.publicKey,
+          identityCounter: findPda([Buffer.from("identity_counter"), walletKey.publicKey.toBuffer()]),
+          ownedIdentity: findPda([Buffer.from("owned_identity"), walletKey.publicKey.toBuffer(), Buffer.alloc(4)]),
+          identityAccount: findPda([Buffer.from("identity"), walletKey.publicKey.toBuffer(), Buffer.alloc(4)]),
+          usernameClaim: usernameClaim,
+          usernameTombstone: findPda([Buffer.from("username_tombstone"), usernameClaim.toBuffer()]),
+          tombstone: findPda([Buffer.from("tombstone"), walletKey.publicKey.toBuffer()]),
+          registryAuthority: findPda([Buffer.from("registry_authority")]),
+          mint: mint,
+          tokenAccount: getAssociatedTokenAddressSync(mint, walletKey.publicKey),
+          identityMintRecord: findPda([Buffer.from("identity_mint"), mint.toBuffer()]),
+          metadataAccount: findPda(
+            [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), mint.toBuffer()],
+            TOKEN_METADATA_PROGRAM_ID
+          ),
+          masterEditionAccount: findPda(
+            [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), mint.toBuffer(), Buffer.from("edition")],
+            TOKEN_METADATA_PROGRAM_ID
+          ),
+          config: configPda,
+          collectionMint: collectionMint,
+          collectionMetadata: findPda(
+            [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), collectionMint.toBuffer()],
+            TOKEN_METADATA_PROGRAM_ID
+          ),
+          collectionMasterEdition: findPda(
+            [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), collectionMint.toBuffer(), Buffer.from("edition")],
+            TOKEN_METADATA_PROGRAM_ID
+          ),
+          identityRegisterProgram: program.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        } as any)
+        .preInstructions([anchor.web3.ComputeBudgetProgram.setComputeUnitLimit({ units: 400_000 })])
+        .signers([walletKey, mintKeypair])
+        .rpc();
+
+      assert.fail("Transaction should have failed (wallet posing as a program)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "ProgramIdentityRequiresCpi",
+        `Expected program error 'ProgramIdentityRequiresCpi', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    console.log("✅ Wallet routed through a wrapper program rejected");
+  });
+  it("5. A program identity can't be handed over to a wallet!", async () => {
+    // --- Arrange ---
+    const walletKey = anchor.web3.Keypair.generate();
+    const sig = await provider.connection.requestAirdrop(walletKey.publicKey, anchor.web3.LAMPORTS_PER_SOL);
+    await provider.connection.confirmTransaction(sig);
+
+    // The vault pays for the pending change itself, so it needs lamports
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        anchor.web3.SystemProgram.transfer({
+          fromPubkey: owner,
+          toPubkey: vaultPda,
+          lamports: anchor.web3.LAMPORTS_PER_SOL / 10,
+        })
+      )
+    );
+
+    // The provider wallet is the registry admin
+    await program.methods
+      .setAuthorityChangeDelay(new anchor.BN(0))
+      .accounts({ admin: owner } as any)
+      .rpc();
+
+    await mockCaller.methods
+      .proposeVaultAuthorityChange(walletKey.publicKey)
+      .accounts({
+        owner: owner,
+        identityAccount: identityPda,
+        config: findPda([Buffer.from("config")]),
+        pendingAuthorityChange: findPda([Buffer.from("authority_change"), identityPda.toBuffer()]),
+        identityRegisterProgram: program.programId,
+      } as any)
+      .rpc();
+
+    const identityData = await program.account.identityAccount.fetch(identityPda);
+
+    // --- Act & Assert ---
+    try {
+      await program.methods
+        .acceptAuthorityChange(null)
+        .accounts({
+          newAuthority: walletKey.publicKey,
+          oldAuthority: vaultPda,
+          identityAccount: identityPda,
+          reputationAccount: reputationPda,
+          usernameClaim: vaultUsernameClaim,
+          mint: identityData.mint,
+          oldTokenAccount: getAssociatedTokenAddressSync(identityData.mint, vaultPda, true),
+          newOwnedIdentity: findPda([Buffer.from("owned_identity"), walletKey.publicKey.toBuffer(), Buffer.alloc(4)]),
+        } as any)
+        .signers([walletKey])
+        .rpc();
+
+      assert.fail("Transaction should have failed (program identity handed to a wallet)!");
+
+    } catch (err: any) {
+      assert.equal(
+        err.error?.errorCode?.code,
+        "ProgramIdentityRequiresCpi",
+        `Expected program error 'ProgramIdentityRequiresCpi', got: ${JSON.stringify(err.error)}`
+      );
+    }
+
+    const identityAfter = await program.account.identityAccount.fetch(identityPda);
+    assert.ok(identityAfter.authority.equals(vaultPda), "Program identity left the vault");
+
+    console.log("✅ Program identity kept away from a wallet");
+  });
+});